@@ -1,13 +1,18 @@
 use axum::{extract, Json};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use tracing::{debug, error, info, warn};
 use crate::api::{requests, worker};
 use crate::api::server::AppState;
+use crate::db::EventRepository;
 use crate::db::sqlite::schema::{Event, EventType};
 
+/// Toggles the subscription named by the message text, failures are logged and reported as 500.
 pub async fn root(
     state: extract::State<AppState>,
     req: Json<requests::TextEventRequest>,
-) -> () {
-    println!("{:#?}", req);
+) -> Result<(), StatusCode> {
+    debug!("got update {:?}", req);
 
     let text = get_text(&req);
 
@@ -19,33 +24,67 @@ pub async fn root(
         meta: None,
     };
 
-    let is_event_exist = |chat_id, typ| {
-        state.db.clone()
-            .lock()
-            .unwrap()
-            .get_event(chat_id, typ).is_some()
+    let (chat_id, typ) = (e.chat_id, e.typ.clone());
+    let is_event_exist = state.db
+        .run(move |repo| repo.get_event(chat_id, typ).map(|e| e.is_some()))
+        .await
+        .map_err(internal_error)?;
+
+    let typ = if is_event_exist {
+        let (chat_id, typ) = (e.chat_id, e.typ.clone());
+        state.db
+            .run(move |repo| repo.delete_event(chat_id, typ))
+            .await
+            .map_err(internal_error)?;
+
+        worker::DataType::Delete
+    } else {
+        let event = e.clone();
+        state.db
+            .run(move |repo| repo.add_event(event))
+            .await
+            .map_err(internal_error)?;
+
+        worker::DataType::Add
+    };
+
+    let action = match typ {
+        worker::DataType::Add => "add",
+        worker::DataType::Delete => "delete",
     };
+    info!("event action={action} chat_id={} type={}", e.chat_id, e.typ);
 
-    if is_event_exist(e.chat_id, e.typ.clone()) {
-        state.db.clone()
-            .lock()
-            .unwrap()
-            .delete_event(e.chat_id, e.typ.clone())
-            .expect("unable to delete event");
+    state.tx.send(worker::Data::new(e, typ)).await
+        .map_err(|err| internal_error(format!("unable to send event to worker: {err}")))?;
+    state.metrics.updates.with_label_values(&[action]).inc();
 
-        state.tx.send(worker::Data::new(e, worker::DataType::Delete)).await.unwrap();
+    Ok(())
+}
 
-        println!("event deleted");
-    } else {
-        state.db.clone()
-            .lock()
-            .unwrap()
-            .add_event(e.clone())
-            .expect("unable to add event");
+fn internal_error(err: String) -> StatusCode {
+    error!("{err}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
 
-        state.tx.send(worker::Data::new(e, worker::DataType::Add)).await.unwrap();
+/// Ready while the db answers, telegram accepts the token and the server isn't shutting down.
+pub async fn readyz(state: extract::State<AppState>) -> servekit::Readiness {
+    servekit::Readiness::new([
+        ("db", state.db.ping().await),
+        ("telegram", match state.metrics.tg_token_valid.get() {
+            1 => Ok(()),
+            _ => Err("telegram token is rejected or not checked yet".to_string()),
+        }),
+        ("shutdown", servekit::check_shutdown(&state.shutdown)),
+    ])
+}
 
-        println!("event created");
+pub async fn metrics(state: extract::State<AppState>) -> impl IntoResponse {
+    match state.metrics.render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(err) => {
+            warn!("{err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
 }
 
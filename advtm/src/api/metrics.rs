@@ -0,0 +1,39 @@
+use prometheus::{Encoder, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Prometheus metrics of the webhook server.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Telegram updates by `action`: add, delete.
+    pub updates: IntCounterVec,
+    /// Set to 0 when telegram rejects the token.
+    pub tg_token_valid: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("advtm".into()), None)
+            .expect("unable to create metrics registry");
+
+        let updates = IntCounterVec::new(
+            Opts::new("tg_updates_total", "Telegram updates handled"),
+            &["action"],
+        ).unwrap();
+        let tg_token_valid = IntGauge::new("tg_token_valid", "Whether telegram accepts the bot token").unwrap();
+
+        registry.register(Box::new(updates.clone())).unwrap();
+        registry.register(Box::new(tg_token_valid.clone())).unwrap();
+
+        Self { registry, updates, tg_token_valid }
+    }
+
+    /// Renders the metrics in the prometheus text format.
+    pub fn render(&self) -> Result<String, String> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|err| format!("unable to encode metrics: {err}"))?;
+
+        String::from_utf8(buf).map_err(|err| format!("unable to encode metrics: {err}"))
+    }
+}
@@ -2,3 +2,4 @@ pub mod server;
 mod requests;
 mod handlers;
 mod worker;
+pub mod metrics;
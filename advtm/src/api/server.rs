@@ -1,42 +1,94 @@
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use axum_server::tls_rustls::RustlsConfig;
+use std::net::SocketAddr;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
 use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, Instrument};
 use crate::api::{handlers, worker};
+use crate::api::metrics::Metrics;
 use crate::db;
 
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
 pub struct Config {
     pub address: String,
-    pub cert_pem_path: String,
-    pub key_pem_path: String,
+    pub listener: servekit::Listener,
     pub db: db::sqlite::Client,
+    /// Stops accepting requests, in-flight ones are given `servekit::SHUTDOWN_TIMEOUT` to finish.
+    pub shutdown: CancellationToken,
+    pub metrics: Metrics,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Arc<Mutex<db::sqlite::Client>>,
+    pub db: db::sqlite::Client,
     pub tx: Sender<worker::Data>,
+    /// Set when serving plain http, `X-Forwarded-For` is trusted from these peers.
+    pub trusted_proxies: Option<servekit::TrustedProxies>,
+    /// Fails readiness once cancelled, so the proxy stops routing here.
+    pub shutdown: CancellationToken,
+    pub metrics: Metrics,
 }
 
 pub async fn run(cfg: Config) {
     let (tx, rx) = tokio::sync::mpsc::channel(1);
 
+    let state = AppState {
+        db: cfg.db,
+        tx,
+        trusted_proxies: match &cfg.listener {
+            servekit::Listener::Http(proxies) => Some(proxies.clone()),
+            _ => None,
+        },
+        shutdown: cfg.shutdown.clone(),
+        metrics: cfg.metrics,
+    };
+
     let app = axum::Router::new()
         .route("/", axum::routing::post(handlers::root))
-        .with_state(AppState {
-            db: Arc::new(Mutex::new(cfg.db)),
-            tx,
-        });
-
-    let tls_cfg = RustlsConfig::from_pem_file(
-        Path::new(&cfg.cert_pem_path),
-        Path::new(&cfg.key_pem_path),
-    ).await.expect("unable to create tls config");
-
-    worker::run(rx);
-
-    axum_server::bind_rustls(
-        cfg.address.parse().expect("unable to parse addr"),
-        tls_cfg,
-    ).serve(app.into_make_service()).await.expect("unable to serve requests");
-}
\ No newline at end of file
+        .route("/healthz", axum::routing::get(servekit::healthz))
+        .route("/readyz", axum::routing::get(handlers::readyz))
+        .route("/metrics", axum::routing::get(handlers::metrics))
+        .layer(middleware::from_fn_with_state(state.clone(), client_layer))
+        .with_state(state);
+
+    let worker = worker::run(rx);
+
+    let address = cfg.address.parse().expect("unable to parse addr");
+
+    servekit::serve(address, cfg.listener, app, cfg.shutdown).await
+        .expect("unable to run web server");
+
+    info!("waiting for the worker to handle queued data...");
+    if tokio::time::timeout(servekit::SHUTDOWN_TIMEOUT, worker).await.is_err() {
+        error!("worker hasn't stopped in time");
+    }
+}
+
+/// Runs the request in a span with the client address, for requests
+/// of trusted proxies it's taken from `X-Forwarded-For`.
+async fn client_layer<B>(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let peer = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+
+    let forwarded = match (&state.trusted_proxies, peer) {
+        (Some(proxies), Some(peer)) if proxies.contains(peer) => request.headers()
+            .get(FORWARDED_FOR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| proxies.forwarded_client(value)),
+        _ => None,
+    };
+
+    let client = forwarded.or(peer).map(|addr| addr.to_string()).unwrap_or_default();
+
+    next.run(request)
+        .instrument(info_span!("request", client))
+        .await
+}
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
 use crate::db::sqlite::schema::Event;
-use crate::service::rate;
 
 pub enum DataType {
     Add,
@@ -19,29 +19,18 @@ impl Data {
     }
 }
 
-pub struct Pool {
-    rx: Receiver<Data>,
-    rate_service: Box<dyn rate::RateProvider>,
-}
-
-impl Pool {
-    pub fn new(
-        rx: Receiver<Data>,
-        rate_service: Box<dyn rate::RateProvider>,
-    ) -> Self {
-        Self {
-            rx,
-            rate_service,
+/// Drains the queued data, subscriptions aren't handled yet.
+/// The task ends once every sender is dropped and the queued data is drained.
+pub fn run(mut rx: Receiver<Data>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(d) = rx.recv().await {
+            let action = match d.typ {
+                DataType::Add => "add",
+                DataType::Delete => "delete",
+            };
+            debug!("got {action} of event for chat_id={}", d.event.chat_id);
         }
-    }
 
-    pub fn run(&mut self) {
-        tokio::spawn(async move {
-            let mem = HashMap::new();
-
-            while let Some(d) = self.rx.recv().await {
-                d.event.chat_id
-            }
-        });
-    }
-}
\ No newline at end of file
+        info!("worker has been stopped");
+    })
+}
@@ -1,4 +1,4 @@
-use frankenstein::{AsyncTelegramApi, AsyncApi, SetWebhookParams};
+use frankenstein::{AsyncTelegramApi, AsyncApi, Error, SetWebhookParams};
 
 pub struct Client {
     api: AsyncApi,
@@ -11,6 +11,17 @@ impl Client {
         }
     }
 
+    /// Fails if telegram rejects the token.
+    pub async fn check_token(&self) -> Result<(), String> {
+        match self.api.get_me().await {
+            Ok(_) => Ok(()),
+            Err(Error::Api(resp)) if resp.error_code == 401 || resp.error_code == 404 => {
+                Err(format!("TG_TOKEN is rejected by telegram: {}", resp.description))
+            }
+            Err(err) => Err(format!("unable to get bot info: {err}")),
+        }
+    }
+
     pub async fn create_web_hook(&self, url: String) {
         self.api.set_webhook(&SetWebhookParams {
             url: url.into(),
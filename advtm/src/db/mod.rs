@@ -1 +1,4 @@
 pub mod sqlite;
+mod repository;
+
+pub use repository::*;
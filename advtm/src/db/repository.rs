@@ -0,0 +1,8 @@
+use crate::db::sqlite::schema::{Event, EventType};
+
+pub trait EventRepository {
+    fn list_events(&self) -> Vec<Event>;
+    fn get_event(&self, chat_id: i64, typ: EventType) -> Result<Option<Event>, String>;
+    fn add_event(&self, e: Event) -> Result<(), String>;
+    fn delete_event(&self, chat_id: i64, typ: EventType) -> Result<(), String>;
+}
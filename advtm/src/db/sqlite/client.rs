@@ -1,15 +1,35 @@
-use rusqlite::Connection;
+use std::time::Duration;
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, DatabaseName, OpenFlags, OptionalExtension};
 use sea_query::{ColumnDef, Expr, OnConflict, Query, SqliteQueryBuilder, Table};
 use sea_query_rusqlite::RusqliteBinder;
+use crate::db::EventRepository;
 use crate::db::sqlite::schema::{Event, EventIden, EventType};
 
+/// Copies the db at `path` into `dst_path` without touching its schema,
+/// so a snapshot can be taken while the app is running.
+pub fn backup(path: &str, dst_path: &str) -> Result<(), String> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+        .map_err(|err| format!("unable to open db path='{path}': {err}"))?
+        .backup(DatabaseName::Main, dst_path, None)
+        .map_err(|err| format!("unable to backup db to path='{dst_path}': {err}"))
+}
+
+#[derive(Clone)]
 pub struct Client {
-    conn: Connection,
+    pool: Pool<SqliteConnectionManager>,
 }
 
 impl Client {
-    pub fn new() -> Self {
-        let conn = Connection::open(format!("{}.db", crate::APP_NAME)).expect("unable to connect db");
+    pub fn new(path: &str) -> Self {
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(|conn| {
+                conn.pragma_update(None, "journal_mode", "WAL")?;
+                conn.busy_timeout(Duration::from_secs(5))
+            });
+        let pool = Pool::new(manager).expect("unable to connect db");
 
         let init_schema = Table::create()
             .table(EventIden::Table)
@@ -25,12 +45,51 @@ impl Client {
             .col(ColumnDef::new(EventIden::Meta).text().null())
             .build(SqliteQueryBuilder);
 
-        conn.execute(&init_schema, []).expect("unable to init schema");
+        pool.get()
+            .expect("unable to get db connection")
+            .execute(&init_schema, [])
+            .expect("unable to init schema");
 
-        Self { conn }
+        Self { pool }
     }
+    /// Called once the server and the worker have stopped, leaves a single
+    /// self-contained `.db` file behind for the next deploy.
+    pub fn checkpoint(&self) -> Result<(), String> {
+        self.pool.get()
+            .map_err(|err| format!("unable to get db connection: {err}"))?
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(|err| format!("unable to checkpoint db: {err}"))
+    }
+
+    /// Readiness probe, fails when the pool is exhausted or the file is unreadable.
+    pub async fn ping(&self) -> Result<(), String> {
+        self.run(|conn| conn
+            .query_row("SELECT 1", [], |_| Ok(()))
+            .map_err(|err| format!("unable to query db: {err}"))
+        ).await
+    }
+
+    /// Gives `f` a connection of the pool on a blocking thread, the webhook
+    /// handler awaits the result instead of locking a shared connection.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, String>
+        where
+            T: Send + 'static,
+            F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()
+                .map_err(|err| format!("unable to get db connection: {err}"))?;
+            f(&conn)
+        })
+            .await
+            .map_err(|err| format!("unable to join db task: {err}"))?
+    }
+}
 
-    pub fn list_events(&self) -> Vec<Event> {
+impl EventRepository for Connection {
+    fn list_events(&self) -> Vec<Event> {
         let (sql, params) = Query::select()
             .from(EventIden::Table)
             .columns([
@@ -43,7 +102,7 @@ impl Client {
             .build_rusqlite(SqliteQueryBuilder);
 
 
-        let mut stmt = self.conn.prepare(&sql).unwrap();
+        let mut stmt = self.prepare(&sql).unwrap();
         let mut rows = stmt.query(params.as_params().as_slice()).unwrap();
 
         let mut res = Vec::new();
@@ -55,7 +114,7 @@ impl Client {
         return res;
     }
 
-    pub fn get_event(&self, chat_id: i64, typ: EventType) -> Option<Event> {
+    fn get_event(&self, chat_id: i64, typ: EventType) -> Result<Option<Event>, String> {
         let (sql, params) = Query::select()
             .from(EventIden::Table)
             .columns([
@@ -69,11 +128,12 @@ impl Client {
             .and_where(Expr::col(EventIden::Type).eq(typ.to_string()))
             .build_rusqlite(SqliteQueryBuilder);
 
-        let mut stmt = self.conn.prepare(&sql).unwrap();
-        stmt.query_row(params.as_params().as_slice(), |row | Ok(Event::from(row))).ok()
+        self.query_row(&sql, params.as_params().as_slice(), |row| Ok(Event::from(row)))
+            .optional()
+            .map_err(|err| format!("unable to get event with chat_id='{chat_id}': {err}"))
     }
 
-    pub fn add_event(&self, e: Event) -> Result<(), String> {
+    fn add_event(&self, e: Event) -> Result<(), String> {
         let (sql, params) = Query::insert()
             .into_table(EventIden::Table)
             .columns([
@@ -91,22 +151,20 @@ impl Client {
             .on_conflict(OnConflict::new().do_nothing().to_owned())
             .build_rusqlite(SqliteQueryBuilder);
 
-        self.conn
-            .execute(&sql, params.as_params().as_slice())
+        self.execute(&sql, params.as_params().as_slice())
             .map_err(|err| format!("unable to insert event: {err}"))?;
 
         Ok(())
     }
 
-    pub fn delete_event(&self, chat_id: i64, typ: EventType) -> Result<(), String> {
+    fn delete_event(&self, chat_id: i64, typ: EventType) -> Result<(), String> {
         let (sql, params) = Query::delete()
             .from_table(EventIden::Table)
             .and_where(Expr::col(EventIden::ChatID).eq(chat_id))
             .and_where(Expr::col(EventIden::Type).eq(typ.to_string()))
             .build_rusqlite(SqliteQueryBuilder);
 
-        self.conn
-            .execute(&sql, params.as_params().as_slice())
+        self.execute(&sql, params.as_params().as_slice())
             .map_err(|err| format!("unable to delete event with chat_id='{chat_id}': {err}"))?;
 
         Ok(())
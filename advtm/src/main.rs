@@ -1,5 +1,8 @@
+use std::env;
+
 use serde::Deserialize;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 use crate::client::telegram;
 
 mod client;
@@ -7,39 +10,123 @@ mod db;
 mod service;
 mod api;
 
-const APP_NAME: &str = "advtm";
-
 #[derive(Deserialize, Debug)]
 struct Config {
     tg_token: String,
     tg_valid_user_ids: String,
+    db_path: String,
     server_address: String,
-    cert_pem_path: String,
-    key_pem_path: String,
+    /// Public url telegram sends updates to, the server address is used if not set.
+    webhook_url: Option<String>,
+    #[serde(default)]
+    server_mode: ServerMode,
+    cert_pem_path: Option<String>,
+    key_pem_path: Option<String>,
+    acme_domains: Option<String>,
+    acme_contact_email: Option<String>,
+    #[serde(default = "default_acme_cache_dir")]
+    acme_cache_dir: String,
+    acme_directory_url: Option<String>,
+    acme_ca_pem_path: Option<String>,
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum ServerMode {
+    #[default]
+    Tls,
+    /// Tls with certificates from an ACME server.
+    Acme,
+    /// Plain http behind a reverse proxy.
+    Http,
+}
+
+fn default_acme_cache_dir() -> String {
+    "./acme".into()
+}
+
+fn default_trusted_proxies() -> String {
+    "127.0.0.1,::1".into()
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    // commands only need the db, so the rest of the env isn't required for them
+    let mut args = env::args().skip(1);
+    if let Some(cmd) = args.next() {
+        let db_path = env::var("DB_PATH").expect("DB_PATH must be provided");
+        match cmd.as_str() {
+            "backup" => {
+                let dst_path = args.next().expect("backup path must be provided");
+                db::sqlite::backup(&db_path, &dst_path).expect("unable to backup db");
+                info!("db has been backed up to path={dst_path}");
+            }
+            _ => panic!("unknown command '{cmd}'"),
+        }
+        return;
+    }
+
     let cfg = envy::from_env::<Config>().expect("unable to parse env variables");
     let tg_valid_user_ids: Vec<String> = cfg.tg_valid_user_ids
         .split(",")
         .map(str::to_string)
         .collect();
 
+    let db = db::sqlite::Client::new(&cfg.db_path);
+
     let telegram = telegram::Client::new(cfg.tg_token);
-    let db = db::sqlite::Client::new();
 
-    telegram.create_web_hook(cfg.server_address.clone()).await;
+    let metrics = api::metrics::Metrics::new();
+    match telegram.check_token().await {
+        Ok(()) => metrics.tg_token_valid.set(1),
+        Err(err) => error!("{err}"),
+    }
+
+    let webhook_url = cfg.webhook_url.unwrap_or_else(|| cfg.server_address.clone());
+    telegram.create_web_hook(webhook_url).await;
+
+    let listener = match cfg.server_mode {
+        ServerMode::Tls => servekit::Listener::Tls {
+            cert_pem_path: cfg.cert_pem_path.expect("CERT_PEM_PATH must be provided for tls mode"),
+            key_pem_path: cfg.key_pem_path.expect("KEY_PEM_PATH must be provided for tls mode"),
+        },
+        ServerMode::Acme => servekit::Listener::Acme(servekit::AcmeConfig {
+            domains: cfg.acme_domains
+                .expect("ACME_DOMAINS must be provided for acme mode")
+                .split(",")
+                .map(str::to_string)
+                .collect(),
+            contact_email: cfg.acme_contact_email,
+            cache_dir: cfg.acme_cache_dir,
+            directory_url: cfg.acme_directory_url,
+            ca_pem_path: cfg.acme_ca_pem_path,
+        }),
+        ServerMode::Http => servekit::Listener::Http(
+            servekit::TrustedProxies::parse(&cfg.trusted_proxies).expect("unable to parse TRUSTED_PROXIES"),
+        ),
+    };
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(servekit::cancel_on_signal(shutdown.clone()));
+
     info!("starting web server on address={}...", cfg.server_address);
 
     api::server::run(api::server::Config {
         address: cfg.server_address,
-        cert_pem_path: cfg.cert_pem_path,
-        key_pem_path: cfg.key_pem_path,
-        db,
+        listener,
+        db: db.clone(),
+        shutdown,
+        metrics,
     }).await;
 
     info!("web server has been closed...");
+
+    match db.checkpoint() {
+        Ok(()) => info!("db has been flushed"),
+        Err(err) => error!("unable to flush db: {err}"),
+    }
 }
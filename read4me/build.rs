@@ -1,9 +1,16 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the server half only backs the fake synthesizer in tests, release builds skip it
+    let fake_server = std::env::var("PROFILE")? != "release";
+    println!("cargo:rustc-check-cfg=cfg(tts_fake_server)");
+    if fake_server {
+        println!("cargo:rustc-cfg=tts_fake_server");
+    }
+
     tonic_build::configure()
-        .build_server(false)
+        .build_server(fake_server)
         .compile(
             &["rpc/proto/yandex-cloud/tts.proto"],
             &["rpc/proto"],
         )?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,261 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Deserialize;
+
+/// Container of synthesised audio. Assets are served by `ServeDir`,
+/// which picks the content type from the file extension.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Wav,
+    Mp3,
+    OggOpus,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Wav => "wav",
+            Format::Mp3 => "mp3",
+            Format::OggOpus => "ogg",
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Format::Wav => "wav",
+            Format::Mp3 => "mp3",
+            Format::OggOpus => "ogg_opus",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<&str> for Format {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "wav" => Ok(Format::Wav),
+            "mp3" => Ok(Format::Mp3),
+            "ogg_opus" => Ok(Format::OggOpus),
+            _ => Err(format!("unknown audio format '{value}'")),
+        }
+    }
+}
+
+pub mod text {
+    /// Splits text into chunks of at most `max_len` chars on sentence boundaries,
+    /// too long sentences are split on whitespace and as a last resort by chars.
+    pub fn split(text: &str, max_len: usize) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut chunk = String::new();
+
+        for sentence in sentences(text) {
+            for part in fit(sentence, max_len) {
+                if !chunk.is_empty() && len(&chunk) + 1 + len(&part) > max_len {
+                    chunks.push(std::mem::take(&mut chunk));
+                }
+                if !chunk.is_empty() {
+                    chunk.push(' ');
+                }
+                chunk.push_str(&part);
+            }
+        }
+
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+
+        chunks
+    }
+
+    /// Returns names of `{name}` placeholders of a template in order of appearance.
+    pub fn variables(template: &str) -> Vec<String> {
+        let mut res: Vec<String> = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            rest = &rest[start + 1..];
+            let Some(end) = rest.find('}') else { break };
+
+            let name = &rest[..end];
+            if !name.is_empty() && !res.iter().any(|v| v == name) {
+                res.push(name.to_string());
+            }
+            rest = &rest[end + 1..];
+        }
+
+        res
+    }
+
+    fn sentences(text: &str) -> Vec<&str> {
+        let mut res = Vec::new();
+        let mut start = 0;
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            let is_end = match c {
+                '\n' => true,
+                '.' | '!' | '?' | '…' => chars.peek().is_none_or(|(_, next)| next.is_whitespace()),
+                _ => false,
+            };
+
+            if is_end {
+                let end = i + c.len_utf8();
+                res.push(text[start..end].trim());
+                start = end;
+            }
+        }
+        res.push(text[start..].trim());
+
+        res.retain(|s| !s.is_empty());
+        res
+    }
+
+    fn fit(sentence: &str, max_len: usize) -> Vec<String> {
+        if len(sentence) <= max_len {
+            return vec![sentence.to_string()];
+        }
+
+        let mut res = Vec::new();
+        let mut part = String::new();
+
+        for word in sentence.split_whitespace() {
+            if !part.is_empty() && len(&part) + 1 + len(word) > max_len {
+                res.push(std::mem::take(&mut part));
+            }
+            if len(word) > max_len {
+                let chars: Vec<char> = word.chars().collect();
+                for piece in chars.chunks(max_len) {
+                    res.push(piece.iter().collect());
+                }
+                continue;
+            }
+            if !part.is_empty() {
+                part.push(' ');
+            }
+            part.push_str(word);
+        }
+
+        if !part.is_empty() {
+            res.push(part);
+        }
+
+        res
+    }
+
+    fn len(s: &str) -> usize {
+        s.chars().count()
+    }
+}
+
+pub mod lang {
+    use whatlang::{Detector, Lang};
+
+    /// Languages with SpeechKit voices as ISO 639-1 codes.
+    pub const LANGS: [&str; 5] = ["ru", "en", "de", "kk", "uz"];
+    /// Language of the default voice.
+    pub const DEFAULT: &str = "ru";
+
+    /// Detects the language of the text among `LANGS`, short phrases
+    /// are mostly told apart by their script. Kazakh isn't detectable
+    /// and can only be set explicitly.
+    pub fn detect(text: &str) -> Option<String> {
+        let detector = Detector::with_allowlist(vec![Lang::Rus, Lang::Eng, Lang::Deu, Lang::Uzb]);
+
+        let code = match detector.detect_lang(text)? {
+            Lang::Rus => "ru",
+            Lang::Eng => "en",
+            Lang::Deu => "de",
+            Lang::Uzb => "uz",
+            _ => return None,
+        };
+
+        Some(code.into())
+    }
+
+    pub fn is_supported(lang: &str) -> bool {
+        LANGS.contains(&lang)
+    }
+}
+
+pub mod wav {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+
+    /// Wraps mono 16-bit little-endian PCM samples into a WAV container.
+    pub fn encode(pcm: &[u8], sample_rate: u32) -> Vec<u8> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = sample_rate * block_align as u32;
+        let data_len = pcm.len() as u32;
+
+        let mut res = Vec::with_capacity(44 + pcm.len());
+        res.extend_from_slice(b"RIFF");
+        res.extend_from_slice(&(36 + data_len).to_le_bytes());
+        res.extend_from_slice(b"WAVE");
+        res.extend_from_slice(b"fmt ");
+        res.extend_from_slice(&16u32.to_le_bytes());
+        res.extend_from_slice(&1u16.to_le_bytes());
+        res.extend_from_slice(&CHANNELS.to_le_bytes());
+        res.extend_from_slice(&sample_rate.to_le_bytes());
+        res.extend_from_slice(&byte_rate.to_le_bytes());
+        res.extend_from_slice(&block_align.to_le_bytes());
+        res.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+        res.extend_from_slice(b"data");
+        res.extend_from_slice(&data_len.to_le_bytes());
+        res.extend_from_slice(pcm);
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audio::{lang, text, wav};
+
+    #[test]
+    fn split_text_on_sentences() {
+        let chunks = text::split("Привет. Как дела? Хорошо!\nИ у меня", 20);
+
+        assert_eq!(chunks, vec!["Привет. Как дела?", "Хорошо! И у меня"]);
+    }
+
+    #[test]
+    fn split_long_sentence() {
+        let chunks = text::split("one two three four, 3.14 five", 10);
+
+        assert_eq!(chunks, vec!["one two", "three", "four, 3.14", "five"]);
+        assert!(text::split("abcdefghijkl", 5).iter().all(|c| c.chars().count() <= 5));
+    }
+
+    #[test]
+    fn template_variables() {
+        let vars = text::variables("Hi {name}, time for {activity}! Bye {name}. {}");
+
+        assert_eq!(vars, vec!["name", "activity"]);
+    }
+
+    #[test]
+    fn detect_lang() {
+        assert_eq!(lang::detect("Доброе утро, как спалось?").as_deref(), Some("ru"));
+        assert_eq!(lang::detect("Good morning, how did you sleep?").as_deref(), Some("en"));
+        assert_eq!(lang::detect("Guten Morgen, wie hast du geschlafen?").as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn encode_wav() {
+        let pcm = [1u8, 0, 2, 0];
+        let res = wav::encode(&pcm, 22050);
+
+        assert_eq!(res.len(), 44 + pcm.len());
+        assert_eq!(&res[0..4], b"RIFF");
+        assert_eq!(&res[8..12], b"WAVE");
+        assert_eq!(u32::from_le_bytes(res[24..28].try_into().unwrap()), 22050);
+        assert_eq!(u32::from_le_bytes(res[40..44].try_into().unwrap()), 4);
+        assert_eq!(&res[44..], &pcm);
+    }
+}
@@ -1,19 +1,181 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Utc};
+
+use crate::audio;
+use crate::rpc::tts;
+
+/// `NotFound` messages name only the missing entity, so they can be shown to users.
+#[derive(Debug)]
+pub enum Error {
+    NotFound(String),
+    /// A unique value, e.g. a voice name, is already taken.
+    Conflict(String),
+    Internal(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NotFound(msg) | Error::Conflict(msg) | Error::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::Internal(msg)
+    }
+}
+
+impl From<Error> for String {
+    fn from(err: Error) -> Self {
+        err.to_string()
+    }
+}
+
+pub trait Repository {
+    fn add_sentence(&self, text: String, lang: Option<String>) -> Result<i32, Error>;
+    /// Adds all sentences with their tags at once, returns ids in the same order.
+    fn import_sentences(&self, sentences: Vec<sqlite::NewSentence>) -> Result<Vec<i32>, Error>;
+    /// Returns uri of the audio which is no longer referenced by any sentence.
+    fn drop_sentence(&self, id: i32) -> Result<Option<String>, Error>;
+    fn get_sentence(&self, id: i32) -> Result<sqlite::Sentence, Error>;
+    /// Finds the oldest sentence with exactly the same text.
+    fn find_sentence(&self, text: String) -> Result<Option<sqlite::Sentence>, Error>;
+    fn list_sentences(&self, filter: sqlite::SentenceFilter) -> Result<sqlite::SentencePage, Error>;
+    /// Links the sentence to the audio, returns uri of the previous audio
+    /// if it's no longer referenced by any sentence.
+    fn update_sentence_audio(
+        &self,
+        id: i32,
+        uri: String,
+        format: audio::Format,
+        audio_key: String,
+    ) -> Result<Option<String>, Error>;
+    fn update_sentence_voice(&self, id: i32, voice_id: Option<i32>) -> Result<(), Error>;
+    /// Replaces the text keeping the previous one in the history and unlinks the cached audio,
+    /// returns uri of the audio if it's no longer referenced by any sentence.
+    /// Only the language is stored if the text is the same.
+    fn update_sentence_text(&self, id: i32, text: String, lang: Option<String>) -> Result<Option<String>, Error>;
+    fn update_sentence_lang(&self, id: i32, lang: Option<String>) -> Result<(), Error>;
+    /// Applies the set fields at once, the text with its language or else the language,
+    /// and the tags. Returns uri of the audio if it's no longer referenced by any sentence.
+    fn update_sentence(&self, id: i32, update: sqlite::SentenceUpdate) -> Result<Option<String>, Error>;
+    fn list_sentence_history(&self, id: i32) -> Result<Vec<sqlite::SentenceEdit>, Error>;
+    fn get_sentence_edit(&self, id: i32, edit_id: i32) -> Result<sqlite::SentenceEdit, Error>;
+    fn update_sentence_tags(&self, id: i32, tags: Vec<String>) -> Result<(), Error>;
+    fn list_tags(&self) -> Result<Vec<String>, Error>;
+
+    fn add_voice(&self, name: String, profile: tts::Voice) -> Result<i32, Error>;
+    fn drop_voice(&self, id: i32) -> Result<(), Error>;
+    fn get_voice(&self, id: i32) -> Result<sqlite::Voice, Error>;
+    fn list_voices(&self) -> Result<Vec<sqlite::Voice>, Error>;
+    fn update_voice(&self, id: i32, name: String, profile: tts::Voice) -> Result<(), Error>;
+
+    fn get_user_voice(&self, user_id: String) -> Result<Option<i32>, Error>;
+    fn update_user_voice(&self, user_id: String, voice_id: Option<i32>) -> Result<(), Error>;
+
+    fn get_lang_voice(&self, lang: String) -> Result<Option<i32>, Error>;
+    fn list_lang_voices(&self) -> Result<Vec<(String, i32)>, Error>;
+    fn update_lang_voice(&self, lang: String, voice_id: Option<i32>) -> Result<(), Error>;
+
+    /// Picks the sentence voice, then the user voice for the default language,
+    /// then the voice of the language and falls back to its built-in voice.
+    fn resolve_voice(&self, voice_id: Option<i32>, lang: Option<&str>, user_id: String) -> Result<tts::Voice, Error> {
+        let lang = lang.unwrap_or(audio::lang::DEFAULT);
+
+        let voice_id = match voice_id {
+            Some(voice_id) => Some(voice_id),
+            None if lang == audio::lang::DEFAULT => match self.get_user_voice(user_id)? {
+                Some(voice_id) => Some(voice_id),
+                None => self.get_lang_voice(lang.into())?,
+            },
+            None => self.get_lang_voice(lang.into())?,
+        };
+
+        match voice_id {
+            Some(voice_id) => Ok(self.get_voice(voice_id)?.profile),
+            None => Ok(tts::Voice::for_lang(lang)),
+        }
+    }
+
+    fn add_template(&self, name: String, text: String, variables: Vec<tts::TemplateVar>) -> Result<i32, Error>;
+    fn drop_template(&self, id: i32) -> Result<(), Error>;
+    fn get_template(&self, id: i32) -> Result<sqlite::Template, Error>;
+    fn list_templates(&self) -> Result<Vec<sqlite::Template>, Error>;
+    fn update_template(
+        &self,
+        id: i32,
+        name: String,
+        text: String,
+        variables: Vec<tts::TemplateVar>,
+    ) -> Result<(), Error>;
+    fn update_template_prompt(&self, id: i32, prompt_uri: Option<String>) -> Result<(), Error>;
+
+    fn get_audio(&self, audio_key: String) -> Result<Option<String>, Error>;
+    /// Uris of all audio files referenced by sentences, templates and telegram voice messages.
+    fn list_audio_uris(&self) -> Result<Vec<String>, Error>;
+
+    /// Queues synthesis of the sentence for the user, a job which is still
+    /// queued or running for them is reused.
+    fn add_job(&self, sentence_id: i32, user_id: String) -> Result<i32, Error>;
+    fn get_job(&self, id: i32) -> Result<sqlite::Job, Error>;
+    /// Marks the oldest queued job which is due as running and counts the attempt.
+    fn take_job(&self) -> Result<Option<sqlite::Job>, Error>;
+    fn update_job(
+        &self,
+        id: i32,
+        status: sqlite::JobStatus,
+        uri: Option<String>,
+        error: Option<String>,
+    ) -> Result<(), Error>;
+    /// Queues the failed job again, it isn't taken before `not_before`.
+    fn retry_job(&self, id: i32, error: String, not_before: DateTime<Utc>) -> Result<(), Error>;
+    /// Jobs left running by a stopped server are queued again.
+    fn requeue_running_jobs(&self) -> Result<usize, Error>;
+    fn drop_finished_jobs(&self, before: DateTime<Utc>) -> Result<usize, Error>;
+
+    /// Telegram file id of the voice message uploaded with the audio.
+    fn get_tg_voice(&self, audio_key: String) -> Result<Option<String>, Error>;
+    fn update_tg_voice(&self, audio_key: String, file_id: String, uri: String) -> Result<(), Error>;
+
+    /// API tokens are stored as hashes, see `http::token`.
+    fn add_api_token(&self, name: String, token_hash: String, user_id: String) -> Result<(), Error>;
+    /// Returns `false` if there is no token with the name.
+    fn drop_api_token(&self, name: String) -> Result<bool, Error>;
+    fn list_api_tokens(&self) -> Result<Vec<sqlite::ApiToken>, Error>;
+    /// Returns id of the user the token is issued for.
+    fn find_api_token_user(&self, token_hash: String) -> Result<Option<String>, Error>;
+}
+
 pub mod sqlite {
-    use rusqlite::{Connection, Row};
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use rusqlite::{Connection, DatabaseName, OpenFlags, OptionalExtension, Row, Transaction, TransactionBehavior};
+    use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
     use sea_query::{
         ColumnDef,
-        ConditionalStatement,
+        Cond,
         Expr,
         Iden,
+        Index,
+        OnConflict,
         Order,
-        OrderedStatement,
         Query,
-        SchemaStatementBuilder,
         SqliteQueryBuilder,
         Table,
     };
     use sea_query_rusqlite::RusqliteBinder;
 
+    use crate::audio;
+    use crate::db::{Error, Repository};
+    use crate::rpc::tts;
+
     #[derive(Iden)]
     enum SentenceIden {
         #[iden = "sentence"]
@@ -21,12 +183,139 @@ pub mod sqlite {
         Id,
         Text,
         Uri,
+        Format,
+        VoiceId,
+        AudioKey,
+        Lang,
+    }
+
+    #[derive(Iden)]
+    enum VoiceIden {
+        #[iden = "voice"]
+        Table,
+        Id,
+        Name,
+        Voice,
+        Role,
+        Speed,
+        Volume,
+        PitchShift,
+    }
+
+    #[derive(Iden)]
+    enum UserVoiceIden {
+        #[iden = "user_voice"]
+        Table,
+        UserId,
+        VoiceId,
+    }
+
+    /// Voice preset used for sentences in the language.
+    #[derive(Iden)]
+    enum LangVoiceIden {
+        #[iden = "lang_voice"]
+        Table,
+        Lang,
+        VoiceId,
+    }
+
+    #[derive(Iden)]
+    enum TemplateIden {
+        #[iden = "template"]
+        Table,
+        Id,
+        Name,
+        Text,
+        PromptUri,
+    }
+
+    #[derive(Iden)]
+    enum TemplateVariableIden {
+        #[iden = "template_variable"]
+        Table,
+        TemplateId,
+        Name,
+        Value,
+        StartMs,
+        LengthMs,
+    }
+
+    #[derive(Iden)]
+    enum TagIden {
+        #[iden = "tag"]
+        Table,
+        SentenceId,
+        Name,
+    }
+
+    /// Previous texts of a sentence.
+    #[derive(Iden)]
+    enum SentenceHistoryIden {
+        #[iden = "sentence_history"]
+        Table,
+        Id,
+        SentenceId,
+        Text,
+        CreatedAt,
+    }
+
+    /// Synthesis of a sentence audio with the voice settings of the user who requested it.
+    #[derive(Iden)]
+    enum JobIden {
+        #[iden = "job"]
+        Table,
+        Id,
+        SentenceId,
+        UserId,
+        Status,
+        Attempts,
+        Uri,
+        Error,
+        CreatedAt,
+        UpdatedAt,
+        NotBefore,
+    }
+
+    /// Voice messages already uploaded to telegram, they are sent again by file id.
+    /// `uri` is the uploaded audio which is kept in the assets.
+    #[derive(Iden)]
+    enum TgVoiceIden {
+        #[iden = "tg_voice"]
+        Table,
+        AudioKey,
+        FileId,
+        Uri,
+    }
+
+    #[derive(Iden)]
+    enum ApiTokenIden {
+        #[iden = "api_token"]
+        Table,
+        Name,
+        TokenHash,
+        UserId,
+        CreatedAt,
+    }
+
+    /// Synthesised audio shared by sentences with the same text, voice and format,
+    /// `refs` is the number of sentences using it.
+    #[derive(Iden)]
+    enum AudioIden {
+        #[iden = "audio"]
+        Table,
+        Key,
+        Uri,
+        Refs,
     }
 
     pub struct Sentence {
         pub id: i32,
         pub text: String,
         pub uri: Option<String>,
+        pub voice_id: Option<i32>,
+        pub audio_key: Option<String>,
+        pub lang: Option<String>,
+        pub tags: Vec<String>,
     }
 
     impl From<&Row<'_>> for Sentence {
@@ -35,17 +324,228 @@ pub mod sqlite {
                 id: row.get_unwrap(SentenceIden::Id.to_string().as_str()),
                 text: row.get_unwrap(SentenceIden::Text.to_string().as_str()),
                 uri: row.get_unwrap(SentenceIden::Uri.to_string().as_str()),
+                voice_id: row.get_unwrap(SentenceIden::VoiceId.to_string().as_str()),
+                audio_key: row.get_unwrap(SentenceIden::AudioKey.to_string().as_str()),
+                lang: row.get_unwrap(SentenceIden::Lang.to_string().as_str()),
+                tags: Vec::new(),
+            }
+        }
+    }
+
+    pub struct NewSentence {
+        pub text: String,
+        pub lang: Option<String>,
+        pub tags: Vec<String>,
+    }
+
+    pub struct SentenceUpdate {
+        pub text: Option<String>,
+        pub lang: Option<String>,
+        pub tags: Option<Vec<String>>,
+    }
+
+    /// Filter of sentences, `text` is matched by words prefixes and
+    /// `before_id` is the cursor of the next page.
+    #[derive(Default)]
+    pub struct SentenceFilter {
+        pub text: Option<String>,
+        pub tag: Option<String>,
+        pub before_id: Option<i32>,
+        pub limit: u64,
+    }
+
+    pub struct SentencePage {
+        pub sentences: Vec<Sentence>,
+        pub next_before_id: Option<i32>,
+    }
+
+    fn sentence_columns() -> [SentenceIden; 6] {
+        [
+            SentenceIden::Id,
+            SentenceIden::Text,
+            SentenceIden::Uri,
+            SentenceIden::VoiceId,
+            SentenceIden::AudioKey,
+            SentenceIden::Lang,
+        ]
+    }
+
+    /// Text a sentence had before the edit made at `created_at`.
+    pub struct SentenceEdit {
+        pub id: i32,
+        pub text: String,
+        pub created_at: DateTime<Utc>,
+    }
+
+    impl From<&Row<'_>> for SentenceEdit {
+        fn from(row: &Row) -> Self {
+            Self {
+                id: row.get_unwrap(SentenceHistoryIden::Id.to_string().as_str()),
+                text: row.get_unwrap(SentenceHistoryIden::Text.to_string().as_str()),
+                created_at: row.get_unwrap(SentenceHistoryIden::CreatedAt.to_string().as_str()),
+            }
+        }
+    }
+
+    fn sentence_edit_columns() -> [SentenceHistoryIden; 3] {
+        [
+            SentenceHistoryIden::Id,
+            SentenceHistoryIden::Text,
+            SentenceHistoryIden::CreatedAt,
+        ]
+    }
+
+    /// Named voice preset which can be chosen per sentence or per user.
+    pub struct Voice {
+        pub id: i32,
+        pub name: String,
+        pub profile: tts::Voice,
+    }
+
+    impl From<&Row<'_>> for Voice {
+        fn from(row: &Row) -> Self {
+            Self {
+                id: row.get_unwrap(VoiceIden::Id.to_string().as_str()),
+                name: row.get_unwrap(VoiceIden::Name.to_string().as_str()),
+                profile: tts::Voice {
+                    voice: row.get_unwrap(VoiceIden::Voice.to_string().as_str()),
+                    role: row.get_unwrap(VoiceIden::Role.to_string().as_str()),
+                    speed: row.get_unwrap(VoiceIden::Speed.to_string().as_str()),
+                    volume: row.get_unwrap(VoiceIden::Volume.to_string().as_str()),
+                    pitch_shift: row.get_unwrap(VoiceIden::PitchShift.to_string().as_str()),
+                },
+            }
+        }
+    }
+
+    fn voice_columns() -> [VoiceIden; 7] {
+        [
+            VoiceIden::Id,
+            VoiceIden::Name,
+            VoiceIden::Voice,
+            VoiceIden::Role,
+            VoiceIden::Speed,
+            VoiceIden::Volume,
+            VoiceIden::PitchShift,
+        ]
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum JobStatus {
+        Queued,
+        Running,
+        Done,
+        Failed,
+    }
+
+    impl Display for JobStatus {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let s = match self {
+                JobStatus::Queued => "queued",
+                JobStatus::Running => "running",
+                JobStatus::Done => "done",
+                JobStatus::Failed => "failed",
+            };
+
+            write!(f, "{}", s)
+        }
+    }
+
+    impl FromSql for JobStatus {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            match value.as_str()? {
+                "queued" => Ok(JobStatus::Queued),
+                "running" => Ok(JobStatus::Running),
+                "done" => Ok(JobStatus::Done),
+                "failed" => Ok(JobStatus::Failed),
+                status => Err(FromSqlError::Other(format!("unknown job status '{status}'").into())),
+            }
+        }
+    }
+
+    /// `uri` is the audio of a done job, `error` is the last failure.
+    pub struct Job {
+        pub id: i32,
+        pub sentence_id: i32,
+        pub user_id: String,
+        pub status: JobStatus,
+        pub attempts: i32,
+        pub uri: Option<String>,
+        pub error: Option<String>,
+    }
+
+    impl From<&Row<'_>> for Job {
+        fn from(row: &Row) -> Self {
+            Self {
+                id: row.get_unwrap(JobIden::Id.to_string().as_str()),
+                sentence_id: row.get_unwrap(JobIden::SentenceId.to_string().as_str()),
+                user_id: row.get_unwrap(JobIden::UserId.to_string().as_str()),
+                status: row.get_unwrap(JobIden::Status.to_string().as_str()),
+                attempts: row.get_unwrap(JobIden::Attempts.to_string().as_str()),
+                uri: row.get_unwrap(JobIden::Uri.to_string().as_str()),
+                error: row.get_unwrap(JobIden::Error.to_string().as_str()),
+            }
+        }
+    }
+
+    /// Token of a script using the API on behalf of the user, the token itself isn't stored.
+    pub struct ApiToken {
+        pub name: String,
+        pub user_id: String,
+        pub created_at: DateTime<Utc>,
+    }
+
+    impl From<&Row<'_>> for ApiToken {
+        fn from(row: &Row) -> Self {
+            Self {
+                name: row.get_unwrap(ApiTokenIden::Name.to_string().as_str()),
+                user_id: row.get_unwrap(ApiTokenIden::UserId.to_string().as_str()),
+                created_at: row.get_unwrap(ApiTokenIden::CreatedAt.to_string().as_str()),
             }
         }
     }
 
+    fn job_columns() -> [JobIden; 7] {
+        [
+            JobIden::Id,
+            JobIden::SentenceId,
+            JobIden::UserId,
+            JobIden::Status,
+            JobIden::Attempts,
+            JobIden::Uri,
+            JobIden::Error,
+        ]
+    }
+
+    impl FromSql for audio::Format {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            audio::Format::try_from(value.as_str()?)
+                .map_err(|err| FromSqlError::Other(err.into()))
+        }
+    }
+
+    /// Copies the db at `path` into `dst_path` without touching its schema,
+    /// so a snapshot can be taken while the app is running.
+    pub fn backup(path: &str, dst_path: &str) -> Result<(), Error> {
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .map_err(|err| Error::Internal(format!("unable to open db path='{path}': {err}")))?
+            .backup(DatabaseName::Main, dst_path, None)
+            .map_err(|err| Error::Internal(format!("unable to backup db to path='{dst_path}': {err}")))
+    }
+
+    #[derive(Clone)]
     pub struct Client {
-        conn: Connection,
+        pool: Pool<SqliteConnectionManager>,
     }
 
     impl Client {
-        pub fn new() -> Self {
-            let conn = Connection::open(format!("{}.db", crate::APP_NAME)).expect("unable to connect db");
+        pub fn new(path: &str) -> Self {
+            let manager = SqliteConnectionManager::file(path)
+                .with_init(|conn| {
+                    conn.pragma_update(None, "journal_mode", "WAL")?;
+                    conn.busy_timeout(Duration::from_secs(5))
+                });
+            let pool = Pool::new(manager).expect("unable to connect db");
 
             let init_schema = Table::create()
                 .table(SentenceIden::Table)
@@ -61,85 +561,1432 @@ pub mod sqlite {
                 .col(ColumnDef::new(SentenceIden::Uri).text().null())
                 .build(SqliteQueryBuilder);
 
+            let conn = pool.get().expect("unable to get db connection");
             conn.execute(&init_schema, []).expect("unable to init schema");
+            migrate(&conn).expect("unable to migrate schema");
 
-            Self { conn }
+            Self { pool }
+        }
+        /// Moves the WAL content into the db file, called on shutdown
+        /// so the file is complete without the `-wal` one.
+        pub fn checkpoint(&self) -> Result<(), Error> {
+            self.pool.get()
+                .map_err(|err| Error::Internal(format!("unable to get db connection: {err}")))?
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+                .map_err(|err| Error::Internal(format!("unable to checkpoint db: {err}")))
         }
 
-        pub fn add_sentence(&self, text: String) -> Result<i32, String> {
-            let sql = Query::insert()
-                .into_table(SentenceIden::Table)
-                .columns([SentenceIden::Text])
-                .values_panic([text.into()])
-                .build_rusqlite(SqliteQueryBuilder);
+        /// Checks that a connection can be taken and queried.
+        pub async fn ping(&self) -> Result<(), Error> {
+            self.run(|conn| conn
+                .query_row("SELECT 1", [], |_| Ok(()))
+                .map_err(|err| Error::Internal(format!("unable to query db: {err}")))
+            ).await
+        }
 
+        /// Runs `f` on a pooled connection inside the blocking thread pool,
+        /// so async handlers never block the runtime on sqlite I/O.
+        pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+            where
+                T: Send + 'static,
+                F: FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+        {
+            let pool = self.pool.clone();
 
-            let mut stmt = self.conn.prepare(&sql.0).expect("unable to prepare stmt");
-            let id = stmt.insert(sql.1.as_params().as_slice())
-                .map_err(|err| format!("unable to insert sentence: {err}"))?;
+            tokio::task::spawn_blocking(move || {
+                let conn = pool.get()
+                    .map_err(|err| Error::Internal(format!("unable to get db connection: {err}")))?;
+                f(&conn)
+            })
+                .await
+                .map_err(|err| Error::Internal(format!("unable to join db task: {err}")))?
+        }
+    }
 
-            Ok(id as i32)
+    /// Text template with `{name}` placeholders, `prompt_uri` points
+    /// to the recorded audio the synthesis is based on.
+    pub struct Template {
+        pub id: i32,
+        pub name: String,
+        pub text: String,
+        pub prompt_uri: Option<String>,
+        pub variables: Vec<tts::TemplateVar>,
+    }
+
+    impl From<&Row<'_>> for Template {
+        fn from(row: &Row) -> Self {
+            Self {
+                id: row.get_unwrap(TemplateIden::Id.to_string().as_str()),
+                name: row.get_unwrap(TemplateIden::Name.to_string().as_str()),
+                text: row.get_unwrap(TemplateIden::Text.to_string().as_str()),
+                prompt_uri: row.get_unwrap(TemplateIden::PromptUri.to_string().as_str()),
+                variables: Vec::new(),
+            }
         }
+    }
 
-        pub fn drop_sentence(&self, id: i32) -> Result<(), String> {
-            let sql = Query::delete()
-                .from_table(SentenceIden::Table)
-                .and_where(Expr::col(SentenceIden::Id).eq(id))
-                .build_rusqlite(SqliteQueryBuilder);
+    fn template_columns() -> [TemplateIden; 4] {
+        [
+            TemplateIden::Id,
+            TemplateIden::Name,
+            TemplateIden::Text,
+            TemplateIden::PromptUri,
+        ]
+    }
 
-            self.conn
-                .execute(&sql.0, sql.1.as_params().as_slice())
-                .map_err(|err| format!("unable to drop sentence id='{id}: {err}'"))?;
+    fn template_variable_from(row: &Row) -> tts::TemplateVar {
+        tts::TemplateVar {
+            name: row.get_unwrap(TemplateVariableIden::Name.to_string().as_str()),
+            value: row.get_unwrap(TemplateVariableIden::Value.to_string().as_str()),
+            start_ms: row.get_unwrap(TemplateVariableIden::StartMs.to_string().as_str()),
+            length_ms: row.get_unwrap(TemplateVariableIden::LengthMs.to_string().as_str()),
+        }
+    }
 
-            Ok(())
+    /// Loads variables of the given templates, `template_id` is `None` for all templates.
+    fn template_variables(conn: &Connection, template_id: Option<i32>) -> Result<Vec<(i32, tts::TemplateVar)>, Error> {
+        let mut query = Query::select();
+        query
+            .columns([
+                TemplateVariableIden::TemplateId,
+                TemplateVariableIden::Name,
+                TemplateVariableIden::Value,
+                TemplateVariableIden::StartMs,
+                TemplateVariableIden::LengthMs,
+            ])
+            .from(TemplateVariableIden::Table)
+            .order_by(TemplateVariableIden::TemplateId, Order::Asc)
+            .order_by(TemplateVariableIden::StartMs, Order::Asc);
+        if let Some(id) = template_id {
+            query.and_where(Expr::col(TemplateVariableIden::TemplateId).eq(id));
         }
+        let sql = query.build_rusqlite(SqliteQueryBuilder);
 
-        pub fn get_sentence(&self, id: i32) -> Result<Sentence, String> {
-            let sql = Query::select()
-                .columns([SentenceIden::Id, SentenceIden::Text, SentenceIden::Uri])
-                .from(SentenceIden::Table)
-                .and_where(Expr::col(SentenceIden::Id).eq(id))
-                .build_rusqlite(SqliteQueryBuilder);
+        let mut stmt = conn.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+        let mut rows = stmt.query(sql.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to list template variables: {err}")))?;
 
-            let mut stmt = self.conn.prepare(sql.0.as_str()).expect("unable to prepare stmt");
-            let res = stmt.query_row(sql.1.as_params().as_slice(), |row| Ok(Sentence::from(row)))
-                .map_err(|err| format!("unable to get sentence id='{id}': {err}"))?;
+        let mut res = Vec::new();
 
-            Ok(res)
+        while let Some(row) = rows.next().map_err(|err| Error::Internal(format!("unable to do next(): {err}")))? {
+            let template_id = row.get_unwrap(TemplateVariableIden::TemplateId.to_string().as_str());
+            res.push((template_id, template_variable_from(row)));
         }
 
-        pub fn list_sentences(&self) -> Result<Vec<Sentence>, String> {
-            let sql = Query::select()
-                .columns([SentenceIden::Id, SentenceIden::Text, SentenceIden::Uri])
-                .from(SentenceIden::Table)
-                .order_by(SentenceIden::Id, Order::Desc)
-                .build_rusqlite(SqliteQueryBuilder);
+        Ok(res)
+    }
 
-            let mut stmt = self.conn.prepare(sql.0.as_str()).expect("unable to prepare stmt");
-            let mut rows = stmt.query(sql.1.as_params().as_slice())
-                .map_err(|err| format!("unable to list sentences: {err}"))?;
+    fn replace_template_variables(
+        conn: &Connection,
+        template_id: i32,
+        variables: Vec<tts::TemplateVar>,
+    ) -> Result<(), Error> {
+        let delete = Query::delete()
+            .from_table(TemplateVariableIden::Table)
+            .and_where(Expr::col(TemplateVariableIden::TemplateId).eq(template_id))
+            .build_rusqlite(SqliteQueryBuilder);
 
-            let mut res = Vec::new();
+        conn.execute(&delete.0, delete.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to drop variables of template id={template_id}: {err}")))?;
 
-            while let Some(row) = rows.next().map_err(|err| format!("unable to do next(): {err}"))? {
-                res.push(Sentence::from(row));
-            }
+        for v in variables {
+            let sql = Query::insert()
+                .into_table(TemplateVariableIden::Table)
+                .columns([
+                    TemplateVariableIden::TemplateId,
+                    TemplateVariableIden::Name,
+                    TemplateVariableIden::Value,
+                    TemplateVariableIden::StartMs,
+                    TemplateVariableIden::LengthMs,
+                ])
+                .values_panic([
+                    template_id.into(),
+                    v.name.into(),
+                    v.value.into(),
+                    v.start_ms.into(),
+                    v.length_ms.into(),
+                ])
+                .build_rusqlite(SqliteQueryBuilder);
 
-            Ok(res)
+            conn.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to insert variable of template id={template_id}: {err}")))?;
         }
 
-        pub fn update_sentence_uri(&self, id: i32, uri: String) -> Result<(), String> {
-            let sql = Query::update()
+        Ok(())
+    }
+
+    /// Applies schema changes made after the initial `sentence` table,
+    /// `user_version` keeps the number of already applied migrations.
+    fn migrate(conn: &Connection) -> Result<(), Error> {
+        let migrations = [
+            Table::alter()
                 .table(SentenceIden::Table)
-                .value(SentenceIden::Uri, uri)
-                .and_where(Expr::col(SentenceIden::Id).eq(id))
-                .build_rusqlite(SqliteQueryBuilder);
+                .add_column(ColumnDef::new(SentenceIden::Format).text().null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(VoiceIden::Table)
+                .col(
+                    ColumnDef::new(VoiceIden::Id)
+                        .integer()
+                        .not_null()
+                        .auto_increment()
+                        .primary_key()
+                )
+                .col(ColumnDef::new(VoiceIden::Name).text().not_null().unique_key())
+                .col(ColumnDef::new(VoiceIden::Voice).text().not_null())
+                .col(ColumnDef::new(VoiceIden::Role).text().null())
+                .col(ColumnDef::new(VoiceIden::Speed).double().not_null())
+                .col(ColumnDef::new(VoiceIden::Volume).double().null())
+                .col(ColumnDef::new(VoiceIden::PitchShift).double().null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(UserVoiceIden::Table)
+                .col(ColumnDef::new(UserVoiceIden::UserId).text().not_null().primary_key())
+                .col(ColumnDef::new(UserVoiceIden::VoiceId).integer().not_null())
+                .build(SqliteQueryBuilder),
+            Table::alter()
+                .table(SentenceIden::Table)
+                .add_column(ColumnDef::new(SentenceIden::VoiceId).integer().null())
+                .build(SqliteQueryBuilder),
+            Table::alter()
+                .table(SentenceIden::Table)
+                .add_column(ColumnDef::new(SentenceIden::AudioKey).text().null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(TemplateIden::Table)
+                .col(
+                    ColumnDef::new(TemplateIden::Id)
+                        .integer()
+                        .not_null()
+                        .auto_increment()
+                        .primary_key()
+                )
+                .col(ColumnDef::new(TemplateIden::Name).text().not_null())
+                .col(ColumnDef::new(TemplateIden::Text).text().not_null())
+                .col(ColumnDef::new(TemplateIden::PromptUri).text().null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(TemplateVariableIden::Table)
+                .col(ColumnDef::new(TemplateVariableIden::TemplateId).integer().not_null())
+                .col(ColumnDef::new(TemplateVariableIden::Name).text().not_null())
+                .col(ColumnDef::new(TemplateVariableIden::Value).text().not_null())
+                .col(ColumnDef::new(TemplateVariableIden::StartMs).integer().null())
+                .col(ColumnDef::new(TemplateVariableIden::LengthMs).integer().null())
+                .primary_key(
+                    Index::create()
+                        .col(TemplateVariableIden::TemplateId)
+                        .col(TemplateVariableIden::Name)
+                )
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(AudioIden::Table)
+                .col(ColumnDef::new(AudioIden::Key).text().not_null().primary_key())
+                .col(ColumnDef::new(AudioIden::Uri).text().not_null())
+                .col(ColumnDef::new(AudioIden::Refs).integer().not_null())
+                .build(SqliteQueryBuilder),
+            // audio cached per sentence id is re-synthesised into the shared cache,
+            // the old files are removed by the assets janitor
+            Query::update()
+                .table(SentenceIden::Table)
+                .value(SentenceIden::Uri, Option::<String>::None)
+                .value(SentenceIden::AudioKey, Option::<String>::None)
+                .to_string(SqliteQueryBuilder),
+            Table::create()
+                .table(SentenceHistoryIden::Table)
+                .col(
+                    ColumnDef::new(SentenceHistoryIden::Id)
+                        .integer()
+                        .not_null()
+                        .auto_increment()
+                        .primary_key()
+                )
+                .col(ColumnDef::new(SentenceHistoryIden::SentenceId).integer().not_null())
+                .col(ColumnDef::new(SentenceHistoryIden::Text).text().not_null())
+                .col(ColumnDef::new(SentenceHistoryIden::CreatedAt).text().not_null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(TagIden::Table)
+                .col(ColumnDef::new(TagIden::SentenceId).integer().not_null())
+                .col(ColumnDef::new(TagIden::Name).text().not_null())
+                .primary_key(
+                    Index::create()
+                        .col(TagIden::SentenceId)
+                        .col(TagIden::Name)
+                )
+                .build(SqliteQueryBuilder),
+            // sea-query doesn't support virtual tables and triggers
+            [
+                "CREATE VIRTUAL TABLE sentence_fts USING fts5(text, content='sentence', content_rowid='id');",
+                "CREATE TRIGGER sentence_fts_insert AFTER INSERT ON sentence BEGIN
+                    INSERT INTO sentence_fts(rowid, text) VALUES (new.id, new.text);
+                END;",
+                "CREATE TRIGGER sentence_fts_delete AFTER DELETE ON sentence BEGIN
+                    INSERT INTO sentence_fts(sentence_fts, rowid, text) VALUES ('delete', old.id, old.text);
+                END;",
+                "CREATE TRIGGER sentence_fts_update AFTER UPDATE OF text ON sentence BEGIN
+                    INSERT INTO sentence_fts(sentence_fts, rowid, text) VALUES ('delete', old.id, old.text);
+                    INSERT INTO sentence_fts(rowid, text) VALUES (new.id, new.text);
+                END;",
+                "INSERT INTO sentence_fts(sentence_fts) VALUES ('rebuild');",
+            ].join("\n"),
+            Table::alter()
+                .table(SentenceIden::Table)
+                .add_column(ColumnDef::new(SentenceIden::Lang).text().null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(LangVoiceIden::Table)
+                .col(ColumnDef::new(LangVoiceIden::Lang).text().not_null().primary_key())
+                .col(ColumnDef::new(LangVoiceIden::VoiceId).integer().not_null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(JobIden::Table)
+                .col(
+                    ColumnDef::new(JobIden::Id)
+                        .integer()
+                        .not_null()
+                        .auto_increment()
+                        .primary_key()
+                )
+                .col(ColumnDef::new(JobIden::SentenceId).integer().not_null())
+                .col(ColumnDef::new(JobIden::UserId).text().not_null())
+                .col(ColumnDef::new(JobIden::Status).text().not_null())
+                .col(ColumnDef::new(JobIden::Attempts).integer().not_null())
+                .col(ColumnDef::new(JobIden::Uri).text().null())
+                .col(ColumnDef::new(JobIden::Error).text().null())
+                .col(ColumnDef::new(JobIden::CreatedAt).text().not_null())
+                .col(ColumnDef::new(JobIden::UpdatedAt).text().not_null())
+                .build(SqliteQueryBuilder),
+            Index::create()
+                .name("job_status_idx")
+                .table(JobIden::Table)
+                .col(JobIden::Status)
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(TgVoiceIden::Table)
+                .col(ColumnDef::new(TgVoiceIden::AudioKey).text().not_null().primary_key())
+                .col(ColumnDef::new(TgVoiceIden::FileId).text().not_null())
+                .build(SqliteQueryBuilder),
+            Table::create()
+                .table(ApiTokenIden::Table)
+                .col(ColumnDef::new(ApiTokenIden::Name).text().not_null().primary_key())
+                .col(ColumnDef::new(ApiTokenIden::TokenHash).text().not_null().unique_key())
+                .col(ColumnDef::new(ApiTokenIden::UserId).text().not_null())
+                .col(ColumnDef::new(ApiTokenIden::CreatedAt).text().not_null())
+                .build(SqliteQueryBuilder),
+            Table::alter()
+                .table(JobIden::Table)
+                .add_column(ColumnDef::new(JobIden::NotBefore).text().null())
+                .build(SqliteQueryBuilder),
+            Table::alter()
+                .table(TgVoiceIden::Table)
+                .add_column(ColumnDef::new(TgVoiceIden::Uri).text().null())
+                .build(SqliteQueryBuilder),
+        ];
 
-            let mut stmt = self.conn.prepare(sql.0.as_str()).expect("unable to prepare stmt");
-            stmt.execute(sql.1.as_params().as_slice())
-                .map_err(|err| format!("unable to update sentence with id={id}: {err}"))?;
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(|err| Error::Internal(format!("unable to get schema version: {err}")))?;
 
-            Ok(())
+        for (i, sql) in migrations.iter().enumerate().skip(version) {
+            conn.execute_batch(sql)
+                .map_err(|err| format!("unable to apply migration #{}: {err}", i + 1))?;
+            conn.pragma_update(None, "user_version", i + 1)
+                .map_err(|err| Error::Internal(format!("unable to set schema version: {err}")))?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the write lock up front, so concurrent writers wait on the busy timeout
+    /// instead of failing with SQLITE_BUSY when a read transaction can't be upgraded.
+    fn write_transaction(conn: &Connection) -> Result<Transaction<'_>, Error> {
+        Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+            .map_err(|err| Error::Internal(format!("unable to begin transaction: {err}")))
+    }
+
+    /// Distinguishes a missing row from failures of the query.
+    fn query_error(err: rusqlite::Error, what: String) -> Error {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => Error::NotFound(format!("{what} is not found")),
+            err => Error::Internal(format!("unable to get {what}: {err}")),
+        }
+    }
+
+    /// An update which changed no rows didn't find the row.
+    fn check_updated(rows: usize, what: String) -> Result<(), Error> {
+        match rows {
+            0 => Err(Error::NotFound(format!("{what} is not found"))),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_unique_violation(err: &rusqlite::Error) -> bool {
+        matches!(
+            err,
+            rusqlite::Error::SqliteFailure(err, _) if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                || err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+        )
+    }
+
+    fn sentence_tags(conn: &Connection, ids: Vec<i32>) -> Result<Vec<(i32, String)>, Error> {
+        let sql = Query::select()
+            .columns([TagIden::SentenceId, TagIden::Name])
+            .from(TagIden::Table)
+            .and_where(Expr::col(TagIden::SentenceId).is_in(ids))
+            .order_by(TagIden::Name, Order::Asc)
+            .build_rusqlite(SqliteQueryBuilder);
+
+        let mut stmt = conn.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+        let rows = stmt.query_map(sql.1.as_params().as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|err| Error::Internal(format!("unable to list sentence tags: {err}")))?;
+
+        rows.collect::<Result<_, _>>()
+            .map_err(|err| Error::Internal(format!("unable to read sentence tag: {err}")))
+    }
+
+    fn replace_sentence_text(conn: &Connection, id: i32, text: String, lang: Option<String>) -> Result<Option<String>, Error> {
+        let s = conn.get_sentence(id)?;
+        if s.text == text {
+            // the audio and history stay, only the language may have been corrected
+            conn.update_sentence_lang(id, lang)?;
+            return Ok(None);
+        }
+
+        let sql = Query::insert()
+            .into_table(SentenceHistoryIden::Table)
+            .columns([
+                SentenceHistoryIden::SentenceId,
+                SentenceHistoryIden::Text,
+                SentenceHistoryIden::CreatedAt,
+            ])
+            .values_panic([id.into(), s.text.into(), Utc::now().into()])
+            .build_rusqlite(SqliteQueryBuilder);
+
+        conn.execute(&sql.0, sql.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to add history of sentence id={id}: {err}")))?;
+
+        let sql = Query::update()
+            .table(SentenceIden::Table)
+            .value(SentenceIden::Text, text)
+            .value(SentenceIden::Lang, lang)
+            .value(SentenceIden::Uri, Option::<String>::None)
+            .value(SentenceIden::AudioKey, Option::<String>::None)
+            .and_where(Expr::col(SentenceIden::Id).eq(id))
+            .build_rusqlite(SqliteQueryBuilder);
+
+        conn.execute(&sql.0, sql.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to update text of sentence id={id}: {err}")))?;
+
+        let orphan = match &s.audio_key {
+            Some(audio_key) => release_audio(conn, audio_key)?,
+            None => None,
+        };
+
+        Ok(orphan)
+    }
+
+    fn replace_sentence_tags(conn: &Connection, id: i32, tags: Vec<String>) -> Result<(), Error> {
+        let sql = Query::delete()
+            .from_table(TagIden::Table)
+            .and_where(Expr::col(TagIden::SentenceId).eq(id))
+            .build_rusqlite(SqliteQueryBuilder);
+
+        conn.execute(&sql.0, sql.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to drop tags of sentence id={id}: {err}")))?;
+
+        for tag in tags {
+            let sql = Query::insert()
+                .into_table(TagIden::Table)
+                .columns([TagIden::SentenceId, TagIden::Name])
+                .values_panic([id.into(), tag.into()])
+                .on_conflict(OnConflict::columns([TagIden::SentenceId, TagIden::Name]).do_nothing().to_owned())
+                .build_rusqlite(SqliteQueryBuilder);
+
+            conn.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to add tag to sentence id={id}: {err}")))?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns user input into a fts5 query matching all words by prefix,
+    /// words are quoted so the query syntax can't break the search.
+    fn fts_query(text: &str) -> Option<String> {
+        let words: Vec<String> = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect();
+
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        }
+    }
+
+    /// Decrements references of the audio, the audio without references
+    /// is deleted and its uri is returned.
+    fn release_audio(conn: &Connection, audio_key: &str) -> Result<Option<String>, Error> {
+        let sql = Query::update()
+            .table(AudioIden::Table)
+            .value(AudioIden::Refs, Expr::col(AudioIden::Refs).sub(1))
+            .and_where(Expr::col(AudioIden::Key).eq(audio_key))
+            .build_rusqlite(SqliteQueryBuilder);
+
+        conn.execute(&sql.0, sql.1.as_params().as_slice())
+            .map_err(|err| Error::Internal(format!("unable to release audio key='{audio_key}': {err}")))?;
+
+        let sql = Query::delete()
+            .from_table(AudioIden::Table)
+            .and_where(Expr::col(AudioIden::Key).eq(audio_key))
+            .and_where(Expr::col(AudioIden::Refs).lte(0))
+            .returning_col(AudioIden::Uri)
+            .build_rusqlite(SqliteQueryBuilder);
+
+        conn.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+            .optional()
+            .map_err(|err| Error::Internal(format!("unable to drop audio key='{audio_key}': {err}")))
+    }
+
+    impl Repository for Connection {
+        fn add_sentence(&self, text: String, lang: Option<String>) -> Result<i32, Error> {
+            let sql = Query::insert()
+                .into_table(SentenceIden::Table)
+                .columns([SentenceIden::Text, SentenceIden::Lang])
+                .values_panic([text.into(), lang.into()])
+                .build_rusqlite(SqliteQueryBuilder);
+
+
+            let mut stmt = self.prepare(&sql.0).expect("unable to prepare stmt");
+            let id = stmt.insert(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to insert sentence: {err}")))?;
+
+            Ok(id as i32)
+        }
+
+        fn import_sentences(&self, sentences: Vec<NewSentence>) -> Result<Vec<i32>, Error> {
+            let tx = write_transaction(self)?;
+
+            let mut ids = Vec::with_capacity(sentences.len());
+            for s in sentences {
+                let id = tx.add_sentence(s.text, s.lang)?;
+                replace_sentence_tags(&tx, id, s.tags)?;
+                ids.push(id);
+            }
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(ids)
+        }
+
+        fn drop_sentence(&self, id: i32) -> Result<Option<String>, Error> {
+            let tx = write_transaction(self)?;
+
+            let s = tx.get_sentence(id)?;
+
+            let sql = Query::delete()
+                .from_table(SentenceIden::Table)
+                .and_where(Expr::col(SentenceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop sentence id='{id}: {err}'")))?;
+
+            let sql = Query::delete()
+                .from_table(SentenceHistoryIden::Table)
+                .and_where(Expr::col(SentenceHistoryIden::SentenceId).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop history of sentence id='{id}': {err}")))?;
+
+            replace_sentence_tags(&tx, id, Vec::new())?;
+
+            let sql = Query::delete()
+                .from_table(JobIden::Table)
+                .and_where(Expr::col(JobIden::SentenceId).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop jobs of sentence id='{id}': {err}")))?;
+
+            let orphan = match &s.audio_key {
+                Some(audio_key) => release_audio(&tx, audio_key)?,
+                None => None,
+            };
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(orphan)
+        }
+
+        fn get_sentence(&self, id: i32) -> Result<Sentence, Error> {
+            let sql = Query::select()
+                .columns(sentence_columns())
+                .from(SentenceIden::Table)
+                .and_where(Expr::col(SentenceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut res = stmt.query_row(sql.1.as_params().as_slice(), |row| Ok(Sentence::from(row)))
+                .map_err(|err| query_error(err, format!("sentence id='{id}'")))?;
+
+            res.tags = sentence_tags(self, vec![id])?
+                .into_iter()
+                .map(|(_, tag)| tag)
+                .collect();
+
+            Ok(res)
+        }
+
+        fn find_sentence(&self, text: String) -> Result<Option<Sentence>, Error> {
+            let sql = Query::select()
+                .column(SentenceIden::Id)
+                .from(SentenceIden::Table)
+                .and_where(Expr::col(SentenceIden::Text).eq(text.as_str()))
+                .order_by(SentenceIden::Id, Order::Asc)
+                .limit(1)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let id: Option<i32> = self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to find sentence: {err}")))?;
+
+            id.map(|id| self.get_sentence(id)).transpose()
+        }
+
+        fn list_sentences(&self, filter: SentenceFilter) -> Result<SentencePage, Error> {
+            let mut query = Query::select();
+            query
+                .columns(sentence_columns())
+                .from(SentenceIden::Table)
+                .order_by(SentenceIden::Id, Order::Desc)
+                .limit(filter.limit + 1);
+
+            if let Some(text) = filter.text.as_deref().and_then(fts_query) {
+                query.and_where(Expr::cust_with_values(
+                    "id IN (SELECT rowid FROM sentence_fts WHERE sentence_fts MATCH ?)",
+                    [text],
+                ));
+            }
+            if let Some(tag) = filter.tag {
+                query.and_where(Expr::col(SentenceIden::Id).in_subquery(
+                    Query::select()
+                        .column(TagIden::SentenceId)
+                        .from(TagIden::Table)
+                        .and_where(Expr::col(TagIden::Name).eq(tag))
+                        .to_owned()
+                ));
+            }
+            if let Some(before_id) = filter.before_id {
+                query.and_where(Expr::col(SentenceIden::Id).lt(before_id));
+            }
+
+            let sql = query.build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut rows = stmt.query(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to list sentences: {err}")))?;
+
+            let mut res = Vec::new();
+
+            while let Some(row) = rows.next().map_err(|err| Error::Internal(format!("unable to do next(): {err}")))? {
+                res.push(Sentence::from(row));
+            }
+
+            let next_before_id = if res.len() as u64 > filter.limit {
+                res.truncate(filter.limit as usize);
+                res.last().map(|s| s.id)
+            } else {
+                None
+            };
+
+            let ids: Vec<i32> = res.iter().map(|s| s.id).collect();
+            for (sentence_id, tag) in sentence_tags(self, ids)? {
+                if let Some(s) = res.iter_mut().find(|s| s.id == sentence_id) {
+                    s.tags.push(tag);
+                }
+            }
+
+            Ok(SentencePage { sentences: res, next_before_id })
+        }
+
+        fn update_sentence_audio(
+            &self,
+            id: i32,
+            uri: String,
+            format: audio::Format,
+            audio_key: String,
+        ) -> Result<Option<String>, Error> {
+            let tx = write_transaction(self)?;
+
+            let prev_key = tx.get_sentence(id)?.audio_key;
+            if prev_key.as_ref() == Some(&audio_key) {
+                return Ok(None);
+            }
+
+            let sql = Query::insert()
+                .into_table(AudioIden::Table)
+                .columns([AudioIden::Key, AudioIden::Uri, AudioIden::Refs])
+                .values_panic([audio_key.clone().into(), uri.clone().into(), 1.into()])
+                .on_conflict(
+                    OnConflict::column(AudioIden::Key)
+                        .value(AudioIden::Refs, Expr::col((AudioIden::Table, AudioIden::Refs)).add(1))
+                        .to_owned()
+                )
+                .build_rusqlite(SqliteQueryBuilder);
+
+            tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to add audio key='{audio_key}': {err}")))?;
+
+            let sql = Query::update()
+                .table(SentenceIden::Table)
+                .value(SentenceIden::Uri, uri)
+                .value(SentenceIden::Format, format.to_string())
+                .value(SentenceIden::AudioKey, audio_key)
+                .and_where(Expr::col(SentenceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update sentence with id={id}: {err}")))?;
+
+            let orphan = match prev_key {
+                Some(prev_key) => release_audio(&tx, &prev_key)?,
+                None => None,
+            };
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(orphan)
+        }
+
+        fn update_sentence_voice(&self, id: i32, voice_id: Option<i32>) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(SentenceIden::Table)
+                .value(SentenceIden::VoiceId, voice_id)
+                .and_where(Expr::col(SentenceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update voice of sentence id={id}: {err}")))?;
+
+            check_updated(rows, format!("sentence id={id}"))
+        }
+
+        fn update_sentence_text(&self, id: i32, text: String, lang: Option<String>) -> Result<Option<String>, Error> {
+            let tx = write_transaction(self)?;
+
+            let orphan = replace_sentence_text(&tx, id, text, lang)?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(orphan)
+        }
+
+        fn update_sentence_lang(&self, id: i32, lang: Option<String>) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(SentenceIden::Table)
+                .value(SentenceIden::Lang, lang)
+                .and_where(Expr::col(SentenceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update language of sentence id={id}: {err}")))?;
+
+            check_updated(rows, format!("sentence id={id}"))
+        }
+
+        fn update_sentence(&self, id: i32, update: SentenceUpdate) -> Result<Option<String>, Error> {
+            let tx = write_transaction(self)?;
+
+            tx.get_sentence(id)?;
+            let orphan = match (update.text, update.lang) {
+                (Some(text), lang) => replace_sentence_text(&tx, id, text, lang)?,
+                (None, Some(lang)) => {
+                    tx.update_sentence_lang(id, Some(lang))?;
+                    None
+                }
+                (None, None) => None,
+            };
+            if let Some(tags) = update.tags {
+                replace_sentence_tags(&tx, id, tags)?;
+            }
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(orphan)
+        }
+
+        fn list_sentence_history(&self, id: i32) -> Result<Vec<SentenceEdit>, Error> {
+            let sql = Query::select()
+                .columns(sentence_edit_columns())
+                .from(SentenceHistoryIden::Table)
+                .and_where(Expr::col(SentenceHistoryIden::SentenceId).eq(id))
+                .order_by(SentenceHistoryIden::Id, Order::Desc)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let rows = stmt.query_map(sql.1.as_params().as_slice(), |row| Ok(SentenceEdit::from(row)))
+                .map_err(|err| Error::Internal(format!("unable to list history of sentence id={id}: {err}")))?;
+
+            rows.collect::<Result<_, _>>()
+                .map_err(|err| Error::Internal(format!("unable to read sentence edit: {err}")))
+        }
+
+        fn get_sentence_edit(&self, id: i32, edit_id: i32) -> Result<SentenceEdit, Error> {
+            let sql = Query::select()
+                .columns(sentence_edit_columns())
+                .from(SentenceHistoryIden::Table)
+                .and_where(Expr::col(SentenceHistoryIden::Id).eq(edit_id))
+                .and_where(Expr::col(SentenceHistoryIden::SentenceId).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| Ok(SentenceEdit::from(row)))
+                .map_err(|err| query_error(err, format!("edit id='{edit_id}' of sentence id='{id}'")))
+        }
+
+        fn update_sentence_tags(&self, id: i32, tags: Vec<String>) -> Result<(), Error> {
+            let tx = write_transaction(self)?;
+
+            // tags of a missing sentence would be left orphaned
+            tx.get_sentence(id)?;
+            replace_sentence_tags(&tx, id, tags)?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))
+        }
+
+        fn list_tags(&self) -> Result<Vec<String>, Error> {
+            let sql = Query::select()
+                .distinct()
+                .column(TagIden::Name)
+                .from(TagIden::Table)
+                .order_by(TagIden::Name, Order::Asc)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let rows = stmt.query_map(sql.1.as_params().as_slice(), |row| row.get(0))
+                .map_err(|err| Error::Internal(format!("unable to list tags: {err}")))?;
+
+            rows.collect::<Result<_, _>>()
+                .map_err(|err| Error::Internal(format!("unable to read tag: {err}")))
+        }
+
+        fn add_voice(&self, name: String, profile: tts::Voice) -> Result<i32, Error> {
+            let sql = Query::insert()
+                .into_table(VoiceIden::Table)
+                .columns([
+                    VoiceIden::Name,
+                    VoiceIden::Voice,
+                    VoiceIden::Role,
+                    VoiceIden::Speed,
+                    VoiceIden::Volume,
+                    VoiceIden::PitchShift,
+                ])
+                .values_panic([
+                    name.clone().into(),
+                    profile.voice.into(),
+                    profile.role.into(),
+                    profile.speed.into(),
+                    profile.volume.into(),
+                    profile.pitch_shift.into(),
+                ])
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(&sql.0).expect("unable to prepare stmt");
+            let id = stmt.insert(sql.1.as_params().as_slice())
+                .map_err(|err| match is_unique_violation(&err) {
+                    true => Error::Conflict(format!("voice name='{name}' already exists")),
+                    false => Error::Internal(format!("unable to insert voice: {err}")),
+                })?;
+
+            Ok(id as i32)
+        }
+
+        fn drop_voice(&self, id: i32) -> Result<(), Error> {
+            let tx = write_transaction(self)?;
+
+            let sqls = [
+                Query::update()
+                    .table(SentenceIden::Table)
+                    .value(SentenceIden::VoiceId, None::<i32>)
+                    .and_where(Expr::col(SentenceIden::VoiceId).eq(id))
+                    .build_rusqlite(SqliteQueryBuilder),
+                Query::delete()
+                    .from_table(UserVoiceIden::Table)
+                    .and_where(Expr::col(UserVoiceIden::VoiceId).eq(id))
+                    .build_rusqlite(SqliteQueryBuilder),
+                Query::delete()
+                    .from_table(LangVoiceIden::Table)
+                    .and_where(Expr::col(LangVoiceIden::VoiceId).eq(id))
+                    .build_rusqlite(SqliteQueryBuilder),
+                Query::delete()
+                    .from_table(VoiceIden::Table)
+                    .and_where(Expr::col(VoiceIden::Id).eq(id))
+                    .build_rusqlite(SqliteQueryBuilder),
+            ];
+
+            let mut rows = 0;
+            for sql in sqls {
+                rows = tx.execute(&sql.0, sql.1.as_params().as_slice())
+                    .map_err(|err| Error::Internal(format!("unable to drop voice id={id}: {err}")))?;
+            }
+            // the voice itself is deleted last
+            check_updated(rows, format!("voice id={id}"))?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))
+        }
+
+        fn get_voice(&self, id: i32) -> Result<Voice, Error> {
+            let sql = Query::select()
+                .columns(voice_columns())
+                .from(VoiceIden::Table)
+                .and_where(Expr::col(VoiceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let res = stmt.query_row(sql.1.as_params().as_slice(), |row| Ok(Voice::from(row)))
+                .map_err(|err| query_error(err, format!("voice id='{id}'")))?;
+
+            Ok(res)
+        }
+
+        fn list_voices(&self) -> Result<Vec<Voice>, Error> {
+            let sql = Query::select()
+                .columns(voice_columns())
+                .from(VoiceIden::Table)
+                .order_by(VoiceIden::Id, Order::Asc)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut rows = stmt.query(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to list voices: {err}")))?;
+
+            let mut res = Vec::new();
+
+            while let Some(row) = rows.next().map_err(|err| Error::Internal(format!("unable to do next(): {err}")))? {
+                res.push(Voice::from(row));
+            }
+
+            Ok(res)
+        }
+
+        fn update_voice(&self, id: i32, name: String, profile: tts::Voice) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(VoiceIden::Table)
+                .value(VoiceIden::Name, name.clone())
+                .value(VoiceIden::Voice, profile.voice)
+                .value(VoiceIden::Role, profile.role)
+                .value(VoiceIden::Speed, profile.speed)
+                .value(VoiceIden::Volume, profile.volume)
+                .value(VoiceIden::PitchShift, profile.pitch_shift)
+                .and_where(Expr::col(VoiceIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| match is_unique_violation(&err) {
+                    true => Error::Conflict(format!("voice name='{name}' already exists")),
+                    false => Error::Internal(format!("unable to update voice id={id}: {err}")),
+                })?;
+
+            check_updated(rows, format!("voice id={id}"))
+        }
+
+        fn get_user_voice(&self, user_id: String) -> Result<Option<i32>, Error> {
+            let sql = Query::select()
+                .column(UserVoiceIden::VoiceId)
+                .from(UserVoiceIden::Table)
+                .and_where(Expr::col(UserVoiceIden::UserId).eq(user_id.as_str()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            stmt.query_row(sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to get voice of user id='{user_id}': {err}")))
+        }
+
+        fn update_user_voice(&self, user_id: String, voice_id: Option<i32>) -> Result<(), Error> {
+            let sql = match voice_id {
+                Some(voice_id) => Query::insert()
+                    .into_table(UserVoiceIden::Table)
+                    .columns([UserVoiceIden::UserId, UserVoiceIden::VoiceId])
+                    .values_panic([user_id.as_str().into(), voice_id.into()])
+                    .on_conflict(
+                        OnConflict::column(UserVoiceIden::UserId)
+                            .update_column(UserVoiceIden::VoiceId)
+                            .to_owned()
+                    )
+                    .build_rusqlite(SqliteQueryBuilder),
+                None => Query::delete()
+                    .from_table(UserVoiceIden::Table)
+                    .and_where(Expr::col(UserVoiceIden::UserId).eq(user_id.as_str()))
+                    .build_rusqlite(SqliteQueryBuilder),
+            };
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update voice of user id='{user_id}': {err}")))?;
+
+            Ok(())
+        }
+
+        fn get_lang_voice(&self, lang: String) -> Result<Option<i32>, Error> {
+            let sql = Query::select()
+                .column(LangVoiceIden::VoiceId)
+                .from(LangVoiceIden::Table)
+                .and_where(Expr::col(LangVoiceIden::Lang).eq(lang.as_str()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            stmt.query_row(sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to get voice of lang='{lang}': {err}")))
+        }
+
+        fn list_lang_voices(&self) -> Result<Vec<(String, i32)>, Error> {
+            let sql = Query::select()
+                .columns([LangVoiceIden::Lang, LangVoiceIden::VoiceId])
+                .from(LangVoiceIden::Table)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let rows = stmt.query_map(sql.1.as_params().as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(|err| Error::Internal(format!("unable to list voices of languages: {err}")))?;
+
+            rows.collect::<Result<_, _>>()
+                .map_err(|err| Error::Internal(format!("unable to read voice of language: {err}")))
+        }
+
+        fn update_lang_voice(&self, lang: String, voice_id: Option<i32>) -> Result<(), Error> {
+            let sql = match voice_id {
+                Some(voice_id) => Query::insert()
+                    .into_table(LangVoiceIden::Table)
+                    .columns([LangVoiceIden::Lang, LangVoiceIden::VoiceId])
+                    .values_panic([lang.as_str().into(), voice_id.into()])
+                    .on_conflict(
+                        OnConflict::column(LangVoiceIden::Lang)
+                            .update_column(LangVoiceIden::VoiceId)
+                            .to_owned()
+                    )
+                    .build_rusqlite(SqliteQueryBuilder),
+                None => Query::delete()
+                    .from_table(LangVoiceIden::Table)
+                    .and_where(Expr::col(LangVoiceIden::Lang).eq(lang.as_str()))
+                    .build_rusqlite(SqliteQueryBuilder),
+            };
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update voice of lang='{lang}': {err}")))?;
+
+            Ok(())
+        }
+
+        fn add_template(&self, name: String, text: String, variables: Vec<tts::TemplateVar>) -> Result<i32, Error> {
+            let tx = write_transaction(self)?;
+
+            let sql = Query::insert()
+                .into_table(TemplateIden::Table)
+                .columns([TemplateIden::Name, TemplateIden::Text])
+                .values_panic([name.into(), text.into()])
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let id = tx.prepare(&sql.0).expect("unable to prepare stmt")
+                .insert(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to insert template: {err}")))? as i32;
+
+            replace_template_variables(&tx, id, variables)?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(id)
+        }
+
+        fn drop_template(&self, id: i32) -> Result<(), Error> {
+            let tx = write_transaction(self)?;
+
+            replace_template_variables(&tx, id, Vec::new())?;
+
+            let sql = Query::delete()
+                .from_table(TemplateIden::Table)
+                .and_where(Expr::col(TemplateIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop template id={id}: {err}")))?;
+            check_updated(rows, format!("template id={id}"))?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))
+        }
+
+        fn get_template(&self, id: i32) -> Result<Template, Error> {
+            let sql = Query::select()
+                .columns(template_columns())
+                .from(TemplateIden::Table)
+                .and_where(Expr::col(TemplateIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut res = stmt.query_row(sql.1.as_params().as_slice(), |row| Ok(Template::from(row)))
+                .map_err(|err| query_error(err, format!("template id='{id}'")))?;
+
+            res.variables = template_variables(self, Some(id))?
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect();
+
+            Ok(res)
+        }
+
+        fn list_templates(&self) -> Result<Vec<Template>, Error> {
+            let sql = Query::select()
+                .columns(template_columns())
+                .from(TemplateIden::Table)
+                .order_by(TemplateIden::Id, Order::Asc)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut rows = stmt.query(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to list templates: {err}")))?;
+
+            let mut res = Vec::new();
+
+            while let Some(row) = rows.next().map_err(|err| Error::Internal(format!("unable to do next(): {err}")))? {
+                res.push(Template::from(row));
+            }
+
+            for (template_id, v) in template_variables(self, None)? {
+                if let Some(t) = res.iter_mut().find(|t| t.id == template_id) {
+                    t.variables.push(v);
+                }
+            }
+
+            Ok(res)
+        }
+
+        fn update_template(
+            &self,
+            id: i32,
+            name: String,
+            text: String,
+            variables: Vec<tts::TemplateVar>,
+        ) -> Result<(), Error> {
+            let tx = write_transaction(self)?;
+
+            let sql = Query::update()
+                .table(TemplateIden::Table)
+                .value(TemplateIden::Name, name)
+                .value(TemplateIden::Text, text)
+                .and_where(Expr::col(TemplateIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = tx.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update template id={id}: {err}")))?;
+            check_updated(rows, format!("template id={id}"))?;
+
+            replace_template_variables(&tx, id, variables)?;
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))
+        }
+
+        fn update_template_prompt(&self, id: i32, prompt_uri: Option<String>) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(TemplateIden::Table)
+                .value(TemplateIden::PromptUri, prompt_uri)
+                .and_where(Expr::col(TemplateIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let rows = self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update prompt of template id={id}: {err}")))?;
+
+            check_updated(rows, format!("template id={id}"))
+        }
+
+        fn get_audio(&self, audio_key: String) -> Result<Option<String>, Error> {
+            let sql = Query::select()
+                .column(AudioIden::Uri)
+                .from(AudioIden::Table)
+                .and_where(Expr::col(AudioIden::Key).eq(audio_key.as_str()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to get audio key='{audio_key}': {err}")))
+        }
+
+        fn list_audio_uris(&self) -> Result<Vec<String>, Error> {
+            let sql = Query::select()
+                .column(AudioIden::Uri)
+                .from(AudioIden::Table)
+                .union(sea_query::UnionType::All, Query::select()
+                    .column(TemplateIden::PromptUri)
+                    .from(TemplateIden::Table)
+                    .and_where(Expr::col(TemplateIden::PromptUri).is_not_null())
+                    .to_owned())
+                .union(sea_query::UnionType::All, Query::select()
+                    .column(TgVoiceIden::Uri)
+                    .from(TgVoiceIden::Table)
+                    .and_where(Expr::col(TgVoiceIden::Uri).is_not_null())
+                    .to_owned())
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let rows = stmt.query_map(sql.1.as_params().as_slice(), |row| row.get(0))
+                .map_err(|err| Error::Internal(format!("unable to list audio: {err}")))?;
+
+            rows.collect::<Result<_, _>>()
+                .map_err(|err| Error::Internal(format!("unable to read audio uri: {err}")))
+        }
+
+        fn add_job(&self, sentence_id: i32, user_id: String) -> Result<i32, Error> {
+            let tx = write_transaction(self)?;
+
+            let sql = Query::select()
+                .column(JobIden::Id)
+                .from(JobIden::Table)
+                .and_where(Expr::col(JobIden::SentenceId).eq(sentence_id))
+                .and_where(Expr::col(JobIden::UserId).eq(user_id.as_str()))
+                .and_where(Expr::col(JobIden::Status).is_in([
+                    JobStatus::Queued.to_string(),
+                    JobStatus::Running.to_string(),
+                ]))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let pending: Option<i32> = tx.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to get job of sentence id='{sentence_id}': {err}")))?;
+
+            let id = match pending {
+                Some(id) => id,
+                None => {
+                    let now = Utc::now();
+                    let sql = Query::insert()
+                        .into_table(JobIden::Table)
+                        .columns([
+                            JobIden::SentenceId,
+                            JobIden::UserId,
+                            JobIden::Status,
+                            JobIden::Attempts,
+                            JobIden::CreatedAt,
+                            JobIden::UpdatedAt,
+                        ])
+                        .values_panic([
+                            sentence_id.into(),
+                            user_id.into(),
+                            JobStatus::Queued.to_string().into(),
+                            0.into(),
+                            now.into(),
+                            now.into(),
+                        ])
+                        .build_rusqlite(SqliteQueryBuilder);
+
+                    let mut stmt = tx.prepare(&sql.0).expect("unable to prepare stmt");
+                    let id = stmt.insert(sql.1.as_params().as_slice())
+                        .map_err(|err| Error::Internal(format!("unable to insert job: {err}")))?;
+                    id as i32
+                }
+            };
+
+            tx.commit().map_err(|err| Error::Internal(format!("unable to commit transaction: {err}")))?;
+
+            Ok(id)
+        }
+
+        fn get_job(&self, id: i32) -> Result<Job, Error> {
+            let sql = Query::select()
+                .columns(job_columns())
+                .from(JobIden::Table)
+                .and_where(Expr::col(JobIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| Ok(Job::from(row)))
+                .map_err(|err| query_error(err, format!("job id='{id}'")))
+        }
+
+        /// A single statement takes the write lock up front, so concurrent workers wait
+        /// on the busy timeout instead of failing to upgrade a read transaction.
+        fn take_job(&self) -> Result<Option<Job>, Error> {
+            let next = Query::select()
+                .column(JobIden::Id)
+                .from(JobIden::Table)
+                .and_where(Expr::col(JobIden::Status).eq(JobStatus::Queued.to_string()))
+                .cond_where(
+                    Cond::any()
+                        .add(Expr::col(JobIden::NotBefore).is_null())
+                        .add(Expr::col(JobIden::NotBefore).lte(Utc::now()))
+                )
+                .order_by(JobIden::Id, Order::Asc)
+                .limit(1)
+                .to_owned();
+
+            let sql = Query::update()
+                .table(JobIden::Table)
+                .value(JobIden::Status, JobStatus::Running.to_string())
+                .value(JobIden::Attempts, Expr::col(JobIden::Attempts).add(1))
+                .value(JobIden::UpdatedAt, Utc::now())
+                .and_where(Expr::col(JobIden::Id).in_subquery(next))
+                .returning(Query::returning().columns(job_columns()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| Ok(Job::from(row)))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to take queued job: {err}")))
+        }
+
+        fn update_job(
+            &self,
+            id: i32,
+            status: JobStatus,
+            uri: Option<String>,
+            error: Option<String>,
+        ) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(JobIden::Table)
+                .value(JobIden::Status, status.to_string())
+                .value(JobIden::Uri, uri)
+                .value(JobIden::Error, error)
+                .value(JobIden::UpdatedAt, Utc::now())
+                .and_where(Expr::col(JobIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update job id='{id}': {err}")))?;
+
+            Ok(())
+        }
+
+        fn retry_job(&self, id: i32, error: String, not_before: DateTime<Utc>) -> Result<(), Error> {
+            let sql = Query::update()
+                .table(JobIden::Table)
+                .value(JobIden::Status, JobStatus::Queued.to_string())
+                .value(JobIden::Error, error)
+                .value(JobIden::NotBefore, not_before)
+                .value(JobIden::UpdatedAt, Utc::now())
+                .and_where(Expr::col(JobIden::Id).eq(id))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to retry job id='{id}': {err}")))?;
+
+            Ok(())
+        }
+
+        fn requeue_running_jobs(&self) -> Result<usize, Error> {
+            let sql = Query::update()
+                .table(JobIden::Table)
+                .value(JobIden::Status, JobStatus::Queued.to_string())
+                .value(JobIden::UpdatedAt, Utc::now())
+                .and_where(Expr::col(JobIden::Status).eq(JobStatus::Running.to_string()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to requeue running jobs: {err}")))
+        }
+
+        fn drop_finished_jobs(&self, before: DateTime<Utc>) -> Result<usize, Error> {
+            let sql = Query::delete()
+                .from_table(JobIden::Table)
+                .and_where(Expr::col(JobIden::Status).is_in([
+                    JobStatus::Done.to_string(),
+                    JobStatus::Failed.to_string(),
+                ]))
+                .and_where(Expr::col(JobIden::UpdatedAt).lt(before))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop finished jobs: {err}")))
+        }
+
+        fn get_tg_voice(&self, audio_key: String) -> Result<Option<String>, Error> {
+            let sql = Query::select()
+                .column(TgVoiceIden::FileId)
+                .from(TgVoiceIden::Table)
+                .and_where(Expr::col(TgVoiceIden::AudioKey).eq(audio_key.as_str()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to get tg voice key='{audio_key}': {err}")))
+        }
+
+        fn update_tg_voice(&self, audio_key: String, file_id: String, uri: String) -> Result<(), Error> {
+            let sql = Query::insert()
+                .into_table(TgVoiceIden::Table)
+                .columns([TgVoiceIden::AudioKey, TgVoiceIden::FileId, TgVoiceIden::Uri])
+                .values_panic([audio_key.as_str().into(), file_id.into(), uri.into()])
+                .on_conflict(
+                    OnConflict::column(TgVoiceIden::AudioKey)
+                        .update_columns([TgVoiceIden::FileId, TgVoiceIden::Uri])
+                        .to_owned()
+                )
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to update tg voice key='{audio_key}': {err}")))?;
+
+            Ok(())
+        }
+
+        fn add_api_token(&self, name: String, token_hash: String, user_id: String) -> Result<(), Error> {
+            let sql = Query::insert()
+                .into_table(ApiTokenIden::Table)
+                .columns([
+                    ApiTokenIden::Name,
+                    ApiTokenIden::TokenHash,
+                    ApiTokenIden::UserId,
+                    ApiTokenIden::CreatedAt,
+                ])
+                .values_panic([name.as_str().into(), token_hash.into(), user_id.into(), Utc::now().into()])
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| match is_unique_violation(&err) {
+                    true => Error::Conflict(format!("api token name='{name}' already exists")),
+                    false => Error::Internal(format!("unable to add api token name='{name}': {err}")),
+                })?;
+
+            Ok(())
+        }
+
+        fn drop_api_token(&self, name: String) -> Result<bool, Error> {
+            let sql = Query::delete()
+                .from_table(ApiTokenIden::Table)
+                .and_where(Expr::col(ApiTokenIden::Name).eq(name.as_str()))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let n = self.execute(&sql.0, sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to drop api token name='{name}': {err}")))?;
+
+            Ok(n > 0)
+        }
+
+        fn list_api_tokens(&self) -> Result<Vec<ApiToken>, Error> {
+            let sql = Query::select()
+                .columns([ApiTokenIden::Name, ApiTokenIden::UserId, ApiTokenIden::CreatedAt])
+                .from(ApiTokenIden::Table)
+                .order_by(ApiTokenIden::Name, Order::Asc)
+                .build_rusqlite(SqliteQueryBuilder);
+
+            let mut stmt = self.prepare(sql.0.as_str()).expect("unable to prepare stmt");
+            let mut rows = stmt.query(sql.1.as_params().as_slice())
+                .map_err(|err| Error::Internal(format!("unable to list api tokens: {err}")))?;
+
+            let mut res = Vec::new();
+
+            while let Some(row) = rows.next().map_err(|err| Error::Internal(format!("unable to do next(): {err}")))? {
+                res.push(ApiToken::from(row));
+            }
+
+            Ok(res)
+        }
+
+        fn find_api_token_user(&self, token_hash: String) -> Result<Option<String>, Error> {
+            let sql = Query::select()
+                .column(ApiTokenIden::UserId)
+                .from(ApiTokenIden::Table)
+                .and_where(Expr::col(ApiTokenIden::TokenHash).eq(token_hash))
+                .build_rusqlite(SqliteQueryBuilder);
+
+            self.query_row(&sql.0, sql.1.as_params().as_slice(), |row| row.get(0))
+                .optional()
+                .map_err(|err| Error::Internal(format!("unable to find api token: {err}")))
         }
     }
 }
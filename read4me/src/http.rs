@@ -1,22 +1,57 @@
 pub mod server {
-    use std::path::Path;
+    use std::collections::HashSet;
     use std::sync::Arc;
+    use std::time::Duration;
 
     use axum::Router;
     use axum::middleware;
-    use axum::routing::{delete, get, post};
-    use axum_server::tls_rustls::RustlsConfig;
-    use tokio::sync::Mutex;
+    use axum::routing::{delete, get, patch, post, put};
+    use tokio::sync::{broadcast, Notify};
+    use tokio_util::sync::CancellationToken;
 
-    use crate::{db, rpc};
+    use tracing::{error, info};
+
+    use crate::{audio, db};
+    use crate::db::Repository;
     use crate::http::fs;
+    use crate::metrics::Metrics;
+    use crate::rpc::tts::SpeechSynthesizer;
+
+    const ASSETS_CLEANUP_PERIOD: Duration = Duration::from_secs(60 * 60);
+    const ASSETS_CLEANUP_GRACE: Duration = Duration::from_secs(10 * 60);
+    /// Template renders are rendered again when played after this age.
+    const TEMPLATE_AUDIO_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+    const JOBS_POLL_PERIOD: Duration = Duration::from_secs(5);
+    const JOBS_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+    const SYNTHESIS_ATTEMPTS: i32 = 3;
+    /// Delay before the second attempt, doubled for each following one.
+    const SYNTHESIS_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+    const EVENTS_CAPACITY: usize = 64;
 
     #[derive(Clone)]
     pub struct AppState {
-        db_client: Arc<Mutex<db::sqlite::Client>>,
-        tts_client: Arc<Mutex<rpc::tts::Client>>,
-        tg_valid_user_ids: Vec<String>,
-        tg_root_user_ids: Vec<String>,
+        db_client: db::sqlite::Client,
+        tts_client: Arc<dyn SpeechSynthesizer>,
+        assets: fs::Assets,
+        audio_format: audio::Format,
+        tg_valid_user_ids: Arc<Vec<String>>,
+        tg_root_user_ids: Arc<Vec<String>>,
+        /// Wakes up synthesis workers when a job is queued.
+        jobs: Arc<Notify>,
+        /// Changes of the library streamed to the open pages.
+        events: broadcast::Sender<response::Event>,
+        /// Set when serving plain http, `X-Forwarded-*` headers are trusted from these peers.
+        trusted_proxies: Option<servekit::TrustedProxies>,
+        /// Cancelled on SIGTERM, ends event streams and stops the workers.
+        shutdown: CancellationToken,
+        metrics: Metrics,
+    }
+
+    impl AppState {
+        fn publish(&self, event: response::Event) {
+            // there are no receivers while nobody has the page open
+            let _ = self.events.send(event);
+        }
     }
 
     mod urls {
@@ -25,8 +60,33 @@ pub mod server {
         pub const SENTENCES: &str = "/sentences";
         pub const ADD_SENTENCE: &str = "/sentences";
         pub const DROP_SENTENCE: &str = "/sentences/:id";
+        pub const UPDATE_SENTENCE: &str = "/sentences/:id";
         pub const PLAY_SENTENCE: &str = "/sentences/:id/play";
+        pub const IMPORT_SENTENCES: &str = "/sentences/import";
+        pub const EXPORT_SENTENCES: &str = "/sentences/export";
+        pub const JOBS: &str = "/jobs";
+        pub const JOB: &str = "/jobs/:id";
+        pub const EVENTS: &str = "/events";
+        pub const SENTENCE_HISTORY: &str = "/sentences/:id/history";
+        pub const REVERT_SENTENCE: &str = "/sentences/:id/history/:edit_id/revert";
+        pub const SENTENCE_VOICE: &str = "/sentences/:id/voice";
+        pub const SENTENCE_TAGS: &str = "/sentences/:id/tags";
+        pub const SENTENCE_LANG: &str = "/sentences/:id/lang";
+        pub const LANG_VOICE: &str = "/settings/langs/:lang/voice";
+        pub const LANG_VOICES: &str = "/settings/langs";
+        pub const VOICES: &str = "/voices";
+        pub const ADD_VOICE: &str = "/voices";
+        pub const VOICE: &str = "/voices/:id";
+        pub const USER_VOICE: &str = "/settings/voice";
+        pub const TEMPLATES: &str = "/templates";
+        pub const ADD_TEMPLATE: &str = "/templates";
+        pub const TEMPLATE: &str = "/templates/:id";
+        pub const TEMPLATE_PROMPT: &str = "/templates/:id/prompt";
+        pub const PLAY_TEMPLATE: &str = "/templates/:id/play";
         pub const ASSETS: &str = "/assets";
+        pub const HEALTHZ: &str = "/healthz";
+        pub const READYZ: &str = "/readyz";
+        pub const METRICS: &str = "/metrics";
     }
 
     mod tmpl {
@@ -44,23 +104,229 @@ pub mod server {
         pub struct SentencesTemplate {
             pub is_admin: bool,
             pub sentences_url: String,
+            pub voices_url: String,
+            pub user_voice_url: String,
+            pub lang_voices_url: String,
+            pub templates_url: String,
+            pub events_url: String,
+            pub query: String,
+            pub tag: String,
+            pub next_url: Option<String>,
             pub sentences: Vec<Sentence>,
+            pub tags: Vec<Tag>,
+            pub voices: Vec<Voice>,
+            pub langs: Vec<String>,
+            pub lang_voices: Vec<LangVoice>,
+            pub templates: Vec<TextTemplate>,
+            pub user_voice_id: Option<i32>,
+        }
+
+        impl SentencesTemplate {
+            pub fn is_user_voice(&self, id: &i32) -> bool {
+                self.user_voice_id == Some(*id)
+            }
         }
 
         pub struct Sentence {
             pub id: i32,
             pub text: String,
+            pub voice_id: Option<i32>,
+            pub lang: String,
+            pub tags: Vec<String>,
+        }
+
+        pub struct LangVoice {
+            pub lang: String,
+            pub voice_id: Option<i32>,
+        }
+
+        impl LangVoice {
+            pub fn has_voice(&self, id: &i32) -> bool {
+                self.voice_id == Some(*id)
+            }
+        }
+
+        /// Filter chip, `url` toggles the tag keeping the search query.
+        pub struct Tag {
+            pub name: String,
+            pub url: String,
+            pub is_active: bool,
+        }
+
+        impl From<crate::db::sqlite::Sentence> for Sentence {
+            fn from(s: crate::db::sqlite::Sentence) -> Self {
+                Sentence {
+                    id: s.id,
+                    lang: s.lang.or_else(|| crate::audio::lang::detect(&s.text)).unwrap_or_default(),
+                    text: s.text,
+                    voice_id: s.voice_id,
+                    tags: s.tags,
+                }
+            }
         }
 
         impl Sentence {
-            pub fn new(id: i32, text: String) -> Self {
-                Sentence { id, text }
+            pub fn is_lang(&self, lang: &str) -> bool {
+                self.lang == lang
+            }
+
+            pub fn has_voice(&self, id: &i32) -> bool {
+                self.voice_id == Some(*id)
+            }
+        }
+
+        /// Voice preset with optional hints rendered as empty strings.
+        pub struct Voice {
+            pub id: i32,
+            pub name: String,
+            pub voice: String,
+            pub role: String,
+            pub speed: f64,
+            pub volume: String,
+            pub pitch_shift: String,
+        }
+
+        pub struct TextTemplate {
+            pub id: i32,
+            pub name: String,
+            pub text: String,
+            pub has_prompt: bool,
+            pub variables: Vec<TextTemplateVar>,
+        }
+
+        /// Template variable with optional offsets rendered as empty strings.
+        pub struct TextTemplateVar {
+            pub name: String,
+            pub value: String,
+            pub start_ms: String,
+            pub length_ms: String,
+        }
+
+        impl From<&crate::db::sqlite::Template> for TextTemplate {
+            fn from(t: &crate::db::sqlite::Template) -> Self {
+                let opt = |val: Option<i64>| val.map(|v| v.to_string()).unwrap_or_default();
+
+                TextTemplate {
+                    id: t.id,
+                    name: t.name.clone(),
+                    text: t.text.clone(),
+                    has_prompt: t.prompt_uri.is_some(),
+                    variables: t.variables
+                        .iter()
+                        .map(|v| TextTemplateVar {
+                            name: v.name.clone(),
+                            value: v.value.clone(),
+                            start_ms: opt(v.start_ms),
+                            length_ms: opt(v.length_ms),
+                        })
+                        .collect(),
+                }
+            }
+        }
+
+        impl From<&crate::db::sqlite::Voice> for Voice {
+            fn from(v: &crate::db::sqlite::Voice) -> Self {
+                let opt = |val: Option<f64>| val.map(|v| v.to_string()).unwrap_or_default();
+
+                Voice {
+                    id: v.id,
+                    name: v.name.clone(),
+                    voice: v.profile.voice.clone(),
+                    role: v.profile.role.clone().unwrap_or_default(),
+                    speed: v.profile.speed,
+                    volume: opt(v.profile.volume),
+                    pitch_shift: opt(v.profile.pitch_shift),
+                }
+            }
+        }
+    }
+
+    pub mod response {
+        use chrono::{DateTime, Utc};
+        use serde::Serialize;
+        use utoipa::ToSchema;
+
+        #[derive(Serialize, Debug)]
+        pub struct SentenceEdit {
+            pub id: i32,
+            pub text: String,
+            pub created_at: DateTime<Utc>,
+        }
+
+        impl From<crate::db::sqlite::SentenceEdit> for SentenceEdit {
+            fn from(e: crate::db::sqlite::SentenceEdit) -> Self {
+                Self { id: e.id, text: e.text, created_at: e.created_at }
+            }
+        }
+
+        /// Server-sent event, `Resync` asks a client which missed events to reload the list.
+        #[derive(Serialize, Clone, Debug)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        pub enum Event {
+            SentenceAdded { id: i32 },
+            SentenceRemoved { id: i32 },
+            AudioReady { sentence_id: i32, job_id: i32, url: String },
+            SynthesisFailed { sentence_id: i32, job_id: i32, error: String },
+            Resync,
+        }
+
+        /// Synthesis job, `url` of the audio is set when the job is done.
+        #[derive(Serialize, ToSchema)]
+        pub struct Job {
+            pub id: i32,
+            pub status: String,
+            pub url: Option<String>,
+            pub error: Option<String>,
+        }
+
+        impl From<crate::db::sqlite::Job> for Job {
+            fn from(j: crate::db::sqlite::Job) -> Self {
+                Self {
+                    id: j.id,
+                    status: j.status.to_string(),
+                    url: j.uri.as_deref().map(super::handlers::asset_url),
+                    error: j.error,
+                }
             }
         }
     }
 
     mod request {
-        use serde::Deserialize;
+        use axum::async_trait;
+        use axum::extract::FromRequestParts;
+        use axum::http::request::Parts;
+        use axum_extra::extract::CookieJar;
+        use serde::{Deserialize, Serialize};
+
+        use crate::http::server::error::AppError;
+
+        /// Telegram id of the user from the cookie set by `handlers::auth`,
+        /// API requests get it from `mdlwr::token_layer`.
+        #[derive(Clone, Debug)]
+        pub struct UserId(pub String);
+
+        #[async_trait]
+        impl<S: Send + Sync> FromRequestParts<S> for UserId {
+            type Rejection = AppError;
+
+            async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+                if let Some(id) = parts.extensions.get::<UserId>() {
+                    return Ok(id.clone());
+                }
+
+                CookieJar::from_headers(&parts.headers)
+                    .get("id")
+                    .map(|id| UserId(id.value().to_string()))
+                    .ok_or(AppError::Unauthorized)
+            }
+        }
+
+        /// Origin of the request, set by `mdlwr::client_layer`.
+        #[derive(Clone, Debug)]
+        pub struct ClientInfo {
+            pub addr: Option<String>,
+            pub scheme: String,
+        }
 
         #[derive(Deserialize, Debug)]
         pub struct Auth {
@@ -70,20 +336,123 @@ pub mod server {
         #[derive(Deserialize, Debug)]
         pub struct AddSentence {
             pub text: String,
+            pub lang: Option<String>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct ListSentences {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub q: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub tag: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub before: Option<i32>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct ImportSentences {
+            pub format: crate::http::library::Format,
+            /// Synthesise audio of the imported sentences in the background.
+            #[serde(default)]
+            pub synthesise: bool,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct ExportSentences {
+            pub format: crate::http::library::Format,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct SetTags {
+            pub tags: Vec<String>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct UpdateSentence {
+            pub text: String,
+            pub lang: Option<String>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct SetLang {
+            pub lang: Option<String>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct Voice {
+            pub name: String,
+            #[serde(flatten)]
+            pub profile: crate::rpc::tts::Voice,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct SetVoice {
+            pub voice_id: Option<i32>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct AddTemplate {
+            pub name: String,
+            pub text: String,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct UpdateTemplate {
+            pub name: String,
+            pub text: String,
+            pub variables: Vec<crate::rpc::tts::TemplateVar>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        pub struct PlayTemplate {
+            pub variables: std::collections::HashMap<String, String>,
         }
     }
 
     mod handlers {
-        use axum::{extract, response::Redirect};
-        use axum::http::StatusCode;
+        use std::convert::Infallible;
+
+        use axum::{extract, Json};
+        use axum::body::Bytes;
+        use axum::http::{header, HeaderName, StatusCode};
+        use axum::response::{IntoResponse, Redirect, Response};
+        use axum::response::sse::{self, Sse};
         use axum_extra::extract::cookie::{Cookie, CookieJar};
+        use tokio_stream::{Stream, StreamExt};
+        use tokio_stream::wrappers::BroadcastStream;
         use tracing::error;
 
-        use crate::http::fs;
-        use crate::http::server::{AppState, request, tmpl, urls};
+        use crate::{audio, db};
+        use crate::db::Repository;
+        use crate::http::{fs, library};
+        use crate::http::server::{AppState, request, response, tmpl, urls};
+        use crate::http::server::error::AppError;
+        use crate::http::server::request::UserId;
+        use crate::rpc::tts;
+
+        const PAGE_SIZE: u64 = 50;
 
         const IMG: &str = "https://static.wixstatic.com/media/82daf4_25d109065ad2499485b2f605379022a4.jpg/v1/fill/w_516,h_560,al_c,lg_1,q_80,enc_auto/82daf4_25d109065ad2499485b2f605379022a4.jpg";
 
+        /// Ready while the db answers, telegram accepts the token, the tts backend
+        /// has a fresh token and the server isn't shutting down.
+        pub async fn readyz(extract::State(state): extract::State<AppState>) -> servekit::Readiness {
+            servekit::Readiness::new([
+                ("db", state.db_client.ping().await.map_err(String::from)),
+                ("telegram", match state.metrics.tg_token_valid.get() {
+                    1 => Ok(()),
+                    _ => Err("telegram token is rejected or not checked yet".to_string()),
+                }),
+                ("tts", state.tts_client.check()),
+                ("shutdown", servekit::check_shutdown(&state.shutdown)),
+            ])
+        }
+
+        pub async fn metrics(extract::State(state): extract::State<AppState>) -> Result<Response, AppError> {
+            let body = state.metrics.render()?;
+            Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response())
+        }
+
         pub async fn root() -> tmpl::IndexTemplate {
             tmpl::IndexTemplate {
                 image_url: IMG.into(),
@@ -93,216 +462,2635 @@ pub mod server {
 
         pub async fn auth(
             extract::State(state): extract::State<AppState>,
+            extract::Extension(client): extract::Extension<request::ClientInfo>,
             extract::Json(req): extract::Json<request::Auth>,
-        ) -> axum::response::Result<(CookieJar, Redirect)> {
+        ) -> Result<(CookieJar, Redirect), AppError> {
             if state.tg_valid_user_ids.contains(&req.tg_id) {
-                let jar = CookieJar::new()
-                    .add(Cookie::new("id", req.tg_id));
+                let cookie = Cookie::build("id", req.tg_id)
+                    .secure(client.scheme == "https")
+                    .finish();
+                let jar = CookieJar::new().add(cookie);
 
                 return Ok((jar, Redirect::to(urls::SENTENCES)));
             }
 
             error!("got invalid tg_id='{}'", req.tg_id);
-            Err(StatusCode::FORBIDDEN.into())
+            Err(AppError::Forbidden)
         }
 
         pub async fn sentences(
             extract::State(state): extract::State<AppState>,
-            jar: CookieJar,
-        ) -> axum::response::Result<tmpl::SentencesTemplate> {
-            let id = jar.get("id")
-                .expect("unable to get cookie").value().to_string();
-            let mut is_admin = false;
-            if state.tg_root_user_ids.contains(&id) {
-                is_admin = true;
-            }
+            UserId(user_id): UserId,
+            extract::Query(req): extract::Query<request::ListSentences>,
+        ) -> Result<tmpl::SentencesTemplate, AppError> {
+            let is_admin = state.tg_root_user_ids.contains(&user_id);
+
+            let filter = db::sqlite::SentenceFilter {
+                text: req.q.clone(),
+                tag: req.tag.clone(),
+                before_id: req.before,
+                limit: PAGE_SIZE,
+            };
 
-            let list = state.db_client
-                .lock().await
-                .list_sentences()?
+            let (page, tags, voices, lang_voices, templates, user_voice_id) = state.db_client
+                .run(move |repo| Ok((
+                    repo.list_sentences(filter)?,
+                    repo.list_tags()?,
+                    repo.list_voices()?,
+                    repo.list_lang_voices()?,
+                    repo.list_templates()?,
+                    repo.get_user_voice(user_id)?,
+                ))).await?;
+
+            let list = page.sentences
+                .into_iter()
+                .map(tmpl::Sentence::from)
+                .collect();
+
+            let lang_voices = audio::lang::LANGS
                 .iter()
-                .map(|e| tmpl::Sentence::new(e.id, e.text.clone()))
+                .map(|lang| tmpl::LangVoice {
+                    lang: lang.to_string(),
+                    voice_id: lang_voices.iter().find(|(l, _)| l == lang).map(|(_, id)| *id),
+                })
+                .collect();
+
+            let tags = tags
+                .into_iter()
+                .map(|name| {
+                    let is_active = req.tag.as_ref() == Some(&name);
+                    let url = sentences_url(&request::ListSentences {
+                        q: req.q.clone(),
+                        tag: if is_active { None } else { Some(name.clone()) },
+                        before: None,
+                    });
+                    tmpl::Tag { name, url, is_active }
+                })
                 .collect();
 
+            let next_url = page.next_before_id.map(|before| sentences_url(&request::ListSentences {
+                before: Some(before),
+                ..req.clone()
+            }));
+
             Ok(tmpl::SentencesTemplate {
                 is_admin,
                 sentences_url: urls::SENTENCES.into(),
+                voices_url: urls::VOICES.into(),
+                user_voice_url: urls::USER_VOICE.into(),
+                lang_voices_url: urls::LANG_VOICES.into(),
+                templates_url: urls::TEMPLATES.into(),
+                events_url: urls::EVENTS.into(),
+                query: req.q.unwrap_or_default(),
+                tag: req.tag.unwrap_or_default(),
+                next_url,
                 sentences: list,
+                tags,
+                voices: voices.iter().map(tmpl::Voice::from).collect(),
+                langs: audio::lang::LANGS.iter().map(|lang| lang.to_string()).collect(),
+                lang_voices,
+                templates: templates.iter().map(tmpl::TextTemplate::from).collect(),
+                user_voice_id,
             })
         }
 
+        pub async fn update_sentence_tags(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::SetTags>,
+        ) -> Result<(), AppError> {
+            let tags = normalize_tags(req.tags);
+
+            state.db_client
+                .run(move |repo| repo.update_sentence_tags(id, tags)).await?;
+            Ok(())
+        }
+
         pub async fn add_sentence(
             extract::State(state): extract::State<AppState>,
+            UserId(user_id): UserId,
             extract::Json(req): extract::Json<request::AddSentence>,
-        ) -> axum::response::Result<String> {
+        ) -> Result<String, AppError> {
+            check_lang(&req.lang)?;
+            let lang = req.lang.or_else(|| audio::lang::detect(&req.text));
+
             let id = state.db_client
-                .lock().await
-                .add_sentence(req.text)?;
+                .run(move |repo| repo.add_sentence(req.text, lang)).await?;
+            state.publish(response::Event::SentenceAdded { id });
+
+            enqueue_synthesis(&state, vec![id], user_id).await?;
+
             Ok(id.to_string())
         }
 
         pub async fn drop_sentence(
             extract::State(state): extract::State<AppState>,
             extract::Path(id): extract::Path<i32>,
-        ) -> axum::response::Result<()> {
-            let s = state.db_client
-                .lock().await
-                .get_sentence(id)?;
+        ) -> Result<(), AppError> {
+            remove_sentence(&state, id).await
+        }
 
-            state.db_client
-                .lock().await
-                .drop_sentence(id)?;
+        pub async fn update_sentence(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::UpdateSentence>,
+        ) -> Result<(), AppError> {
+            edit_sentence(&state, id, req.text, req.lang).await
+        }
+
+        pub async fn remove_sentence(state: &AppState, id: i32) -> Result<(), AppError> {
+            let orphan = state.db_client
+                .run(move |repo| repo.drop_sentence(id)).await?;
+            state.publish(response::Event::SentenceRemoved { id });
 
-            if s.uri.is_some() {
-                fs::drop_audio(id).await?;
+            if let Some(uri) = orphan {
+                state.assets.drop_audio(&uri).await?;
             }
 
             Ok(())
         }
 
-        pub async fn play_sentence(
+        /// Replaces the text, the language is detected unless it's set.
+        pub async fn edit_sentence(
+            state: &AppState,
+            id: i32,
+            text: String,
+            lang: Option<String>,
+        ) -> Result<(), AppError> {
+            check_lang(&lang)?;
+            let lang = lang.or_else(|| audio::lang::detect(&text));
+
+            let orphan = state.db_client
+                .run(move |repo| repo.update_sentence_text(id, text, lang)).await?;
+
+            if let Some(uri) = orphan {
+                state.assets.drop_audio(&uri).await?;
+            }
+
+            Ok(())
+        }
+
+        pub async fn sentence_history(
             extract::State(state): extract::State<AppState>,
             extract::Path(id): extract::Path<i32>,
-        ) -> axum::response::Result<String> {
-            let s = state.db_client
-                .lock().await
-                .get_sentence(id)?;
+        ) -> Result<Json<Vec<response::SentenceEdit>>, AppError> {
+            let history = state.db_client
+                .run(move |repo| repo.list_sentence_history(id)).await?;
 
-            let get_url = |uri| {
-                format!("{}/{}", urls::ASSETS, uri)
-            };
+            Ok(Json(history.into_iter().map(response::SentenceEdit::from).collect()))
+        }
 
-            if let Some(uri) = s.uri {
-                return Ok(get_url(uri));
+        /// Restores the text of the edit, the current text goes to the history as well.
+        pub async fn revert_sentence(
+            extract::State(state): extract::State<AppState>,
+            extract::Path((id, edit_id)): extract::Path<(i32, i32)>,
+        ) -> Result<(), AppError> {
+            let orphan = state.db_client
+                .run(move |repo| {
+                    let edit = repo.get_sentence_edit(id, edit_id)?;
+                    let lang = audio::lang::detect(&edit.text);
+                    repo.update_sentence_text(id, edit.text, lang)
+                }).await?;
+
+            if let Some(uri) = orphan {
+                state.assets.drop_audio(&uri).await?;
             }
 
-            let audio = state.tts_client
-                .lock().await
-                .synthesise_text(s.text).await
-                .map_err(|err| format!("unable to synthesise text: {err}"))?;
+            Ok(())
+        }
 
-            let uri = fs::add_audio(s.id, audio).await?;
+        pub async fn play_sentence(
+            extract::State(state): extract::State<AppState>,
+            UserId(user_id): UserId,
+            extract::Path(id): extract::Path<i32>,
+        ) -> Result<Response, AppError> {
+            if let Some(uri) = sentence_audio(&state, id, user_id.clone(), false).await? {
+                return Ok(asset_url(&uri).into_response());
+            }
 
-            state.db_client
-                .lock().await
-                .update_sentence_uri(id, uri.clone())?;
+            let job_ids = enqueue_synthesis(&state, vec![id], user_id).await?;
+            let job_url = format!("{}/{}", urls::JOBS, job_ids[0]);
 
-            Ok(get_url(uri))
+            Ok((StatusCode::ACCEPTED, [(header::LOCATION, job_url.clone())], job_url).into_response())
         }
-    }
 
-    mod mdlwr {
-        use axum::extract;
-        use axum::http::{Request, StatusCode};
-        use axum::middleware::Next;
-        use axum::response::Response;
-        use axum_extra::extract::CookieJar;
-        use tracing::error;
+        pub async fn events(
+            extract::State(state): extract::State<AppState>,
+        ) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+            let stream = BroadcastStream::new(state.events.subscribe())
+                .map(|event| {
+                    let event = event.unwrap_or(response::Event::Resync);
+                    let data = serde_json::to_string(&event).expect("unable to serialize event");
+                    Ok(sse::Event::default().data(data))
+                });
+            // the stream never ends by itself and would hold the graceful shutdown,
+            // pages reconnect to the restarted server
+            let stream = futures_util::StreamExt::take_until(stream, state.shutdown.clone().cancelled_owned());
 
-        use crate::http::server::AppState;
+            Sse::new(stream).keep_alive(sse::KeepAlive::default())
+        }
 
-        pub async fn auth_layer<B>(
+        pub async fn job(
             extract::State(state): extract::State<AppState>,
-            jar: CookieJar,
-            request: Request<B>,
-            next: Next<B>,
-        ) -> Result<Response, StatusCode> {
-            if let Some(id) = jar.get("id") {
-                let id = id.value().to_string();
-                if state.tg_valid_user_ids.contains(&id) {
-                    let response = next.run(request).await;
-                    return Ok(response);
-                }
+            extract::Path(id): extract::Path<i32>,
+        ) -> Result<Json<response::Job>, AppError> {
+            let job = state.db_client
+                .run(move |repo| repo.get_job(id)).await?;
+
+            Ok(Json(response::Job::from(job)))
+        }
+
+        /// Adds sentences from the file, returns their ids.
+        pub async fn import_sentences(
+            extract::State(state): extract::State<AppState>,
+            UserId(user_id): UserId,
+            extract::Query(req): extract::Query<request::ImportSentences>,
+            body: Bytes,
+        ) -> Result<Json<Vec<i32>>, AppError> {
+            let items = library::parse(req.format, &body)
+                .map_err(AppError::BadRequest)?;
 
-                error!("got user with invalid id='{}'", id);
+            let mut sentences = Vec::with_capacity(items.len());
+            for item in items {
+                check_lang(&item.lang)?;
+                sentences.push(db::sqlite::NewSentence {
+                    lang: item.lang.or_else(|| audio::lang::detect(&item.text)),
+                    text: item.text,
+                    tags: normalize_tags(item.tags),
+                });
             }
 
-            Err(StatusCode::UNAUTHORIZED)
+            let ids = state.db_client
+                .run(move |repo| repo.import_sentences(sentences)).await?;
+            for id in &ids {
+                state.publish(response::Event::SentenceAdded { id: *id });
+            }
+
+            if req.synthesise {
+                enqueue_synthesis(&state, ids.clone(), user_id).await?;
+            }
+
+            Ok(Json(ids))
         }
-    }
 
-    pub async fn init(
-        db_client: db::sqlite::Client,
-        tts_client: rpc::tts::Client,
-        tg_valid_user_ids: Vec<String>,
-        tg_root_user_ids: Vec<String>,
-        addr: &str,
-        cert_pem_path: &str,
-        key_pem_path: &str,
-    ) {
-        let cfg = RustlsConfig::from_pem_file(
-            Path::new(cert_pem_path),
-            Path::new(key_pem_path),
-        ).await.expect("unable to create tls config");
+        pub async fn export_sentences(
+            extract::State(state): extract::State<AppState>,
+            extract::Query(req): extract::Query<request::ExportSentences>,
+        ) -> Result<([(HeaderName, String); 2], Vec<u8>), AppError> {
+            let sentences = state.db_client
+                .run(|repo| {
+                    let mut res = Vec::new();
+                    let mut before_id = None;
+                    loop {
+                        let page = repo.list_sentences(db::sqlite::SentenceFilter {
+                            before_id,
+                            limit: PAGE_SIZE,
+                            ..Default::default()
+                        })?;
+                        res.extend(page.sentences);
 
-        let state = AppState {
-            db_client: Arc::new(Mutex::new(db_client)),
-            tts_client: Arc::new(Mutex::new(tts_client)),
-            tg_valid_user_ids,
-            tg_root_user_ids,
-        };
+                        match page.next_before_id {
+                            Some(id) => before_id = Some(id),
+                            None => return Ok(res),
+                        }
+                    }
+                }).await?;
 
-        let auth_middleware = middleware::from_fn_with_state(
-            state.clone(),
-            mdlwr::auth_layer,
-        );
+            // oldest first, so an import of the file keeps the order
+            let items: Vec<library::Item> = sentences
+                .into_iter()
+                .rev()
+                .map(|s| library::Item { text: s.text, lang: s.lang, tags: s.tags })
+                .collect();
 
-        let app = Router::new()
-            .route(urls::ROOT, get(handlers::root))
-            .route(urls::AUTH, post(handlers::auth))
-            .route(urls::SENTENCES, get(handlers::sentences)
-                .route_layer(auth_middleware.clone()),
-            )
-            .route(urls::ADD_SENTENCE, post(handlers::add_sentence)
-                .route_layer(auth_middleware.clone()),
-            )
-            .route(urls::DROP_SENTENCE, delete(handlers::drop_sentence)
-                .route_layer(auth_middleware.clone()),
-            )
-            .route(urls::PLAY_SENTENCE, post(handlers::play_sentence)
-                .route_layer(auth_middleware),
-            )
-            .nest_service(urls::ASSETS, fs::serve_dir().await)
-            .with_state(state);
+            let data = library::render(req.format, &items)?;
+            let headers = [
+                (header::CONTENT_TYPE, req.format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"sentences.{}\"", req.format.extension()),
+                ),
+            ];
 
+            Ok((headers, data))
+        }
 
-        axum_server::bind_rustls(addr.to_string().parse().expect("invalid address"), cfg)
-            .serve(app.into_make_service()).await.unwrap();
-    }
-}
+        pub fn asset_url(uri: &str) -> String {
+            format!("{}/{}", urls::ASSETS, uri)
+        }
 
-mod fs {
-    use tower_http::services::ServeDir;
+        /// Queues synthesis of the sentences for the user and wakes up the workers, returns job ids.
+        pub async fn enqueue_synthesis(state: &AppState, ids: Vec<i32>, user_id: String) -> Result<Vec<i32>, AppError> {
+            let job_ids = state.db_client
+                .run(move |repo| ids
+                    .into_iter()
+                    .map(|id| repo.add_job(id, user_id.clone()))
+                    .collect()
+                ).await?;
 
-    const ASSETS_DIR: &str = "./assets";
+            for _ in &job_ids {
+                state.jobs.notify_one();
+            }
 
-    pub async fn serve_dir() -> ServeDir {
-        tokio::fs::create_dir_all(ASSETS_DIR)
-            .await
-            .expect("unable to create dir for storing assets");
+            Ok(job_ids)
+        }
 
-        ServeDir::new(ASSETS_DIR)
-    }
+        /// Returns uri of the sentence audio voiced the way the user hears it.
+        /// Missing audio is synthesised only if `synthesise` is set, otherwise `None` is returned.
+        pub async fn sentence_audio(
+            state: &AppState,
+            id: i32,
+            user_id: String,
+            synthesise: bool,
+        ) -> Result<Option<String>, AppError> {
+            let (s, voice) = state.db_client
+                .run(move |repo| {
+                    let s = repo.get_sentence(id)?;
+                    let lang = s.lang.clone().or_else(|| audio::lang::detect(&s.text));
+                    let voice = repo.resolve_voice(s.voice_id, lang.as_deref(), user_id)?;
+                    Ok((s, voice))
+                }).await?;
 
-    pub async fn add_audio(id: i32, audio: Vec<u8>) -> Result<String, String> {
-        tokio::fs::write(audio_name_path(id), audio).await
-            .map_err(|err| format!("unable to save audio: {err}"))?;
+            let format = state.audio_format;
+            let audio_key = fs::audio_key(&s.text, &voice, format);
 
-        Ok(audio_name(id))
-    }
+            if let Some(uri) = &s.uri {
+                if s.audio_key.as_ref() == Some(&audio_key) && state.assets.is_audio_exist(uri).await {
+                    state.metrics.cache_hit(true);
+                    return Ok(Some(uri.clone()));
+                }
+            }
 
-    pub async fn drop_audio(id: i32) -> Result<(), String> {
-        tokio::fs::remove_file(audio_name_path(id))
-            .await
-            .map_err(|err| format!("unable to drop audio with id={id}: {err}"))
-    }
+            let key = audio_key.clone();
+            let cached = state.db_client
+                .run(move |repo| repo.get_audio(key)).await?;
 
+            let uri = match cached {
+                Some(uri) if state.assets.is_audio_exist(&uri).await => {
+                    state.metrics.cache_hit(true);
+                    uri
+                }
+                _ if !synthesise => return Ok(None),
+                _ => {
+                    state.metrics.cache_hit(false);
+                    let audio = state.metrics
+                        .time_synthesis(state.tts_client.synthesise_text(s.text, &voice, format)).await
+                        .map_err(|err| AppError::Tts(format!("unable to synthesise text: {err}")))?;
 
-    fn audio_name(id: i32) -> String {
-        format!("{id}.mp3")
-    }
+                    state.assets.add_audio(&audio_key, format, audio).await?
+                }
+            };
 
-    fn audio_name_path(id: i32) -> String {
-        format!("{ASSETS_DIR}/{}", audio_name(id))
-    }
-}
\ No newline at end of file
+            let linked = uri.clone();
+            let orphan = state.db_client
+                .run(move |repo| repo.update_sentence_audio(id, linked, format, audio_key)).await?;
+
+            if let Some(orphan) = orphan {
+                state.assets.drop_audio(&orphan).await?;
+            }
+
+            Ok(Some(uri))
+        }
+
+        pub async fn add_template(
+            extract::State(state): extract::State<AppState>,
+            extract::Json(req): extract::Json<request::AddTemplate>,
+        ) -> Result<String, AppError> {
+            let variables = template_variables(&req.text, Vec::new());
+            let id = state.db_client
+                .run(move |repo| repo.add_template(req.name, req.text, variables)).await?;
+            Ok(id.to_string())
+        }
+
+        pub async fn update_template(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::UpdateTemplate>,
+        ) -> Result<(), AppError> {
+            let variables = template_variables(&req.text, req.variables);
+            state.db_client
+                .run(move |repo| repo.update_template(id, req.name, req.text, variables)).await?;
+            Ok(())
+        }
+
+        pub async fn drop_template(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+        ) -> Result<(), AppError> {
+            let t = state.db_client
+                .run(move |repo| {
+                    let t = repo.get_template(id)?;
+                    repo.drop_template(id)?;
+                    Ok(t)
+                }).await?;
+
+            if let Some(uri) = t.prompt_uri {
+                state.assets.drop_audio(&uri).await?;
+            }
+
+            Ok(())
+        }
+
+        pub async fn update_template_prompt(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            body: Bytes,
+        ) -> Result<(), AppError> {
+            if !body.starts_with(b"RIFF") {
+                return Err(AppError::BadRequest("prompt must be a wav file".into()));
+            }
+            // the prompt of a missing template isn't stored
+            state.db_client.run(move |repo| repo.get_template(id)).await?;
+
+            let uri = state.assets.add_prompt(id, body.to_vec()).await?;
+            state.db_client
+                .run(move |repo| repo.update_template_prompt(id, Some(uri))).await?;
+            Ok(())
+        }
+
+        pub async fn drop_template_prompt(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+        ) -> Result<(), AppError> {
+            let t = state.db_client
+                .run(move |repo| {
+                    let t = repo.get_template(id)?;
+                    repo.update_template_prompt(id, None)?;
+                    Ok(t)
+                }).await?;
+
+            if let Some(uri) = t.prompt_uri {
+                state.assets.drop_audio(&uri).await?;
+            }
+
+            Ok(())
+        }
+
+        pub async fn play_template(
+            extract::State(state): extract::State<AppState>,
+            UserId(user_id): UserId,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(mut req): extract::Json<request::PlayTemplate>,
+        ) -> Result<String, AppError> {
+            let (t, voice) = state.db_client
+                .run(move |repo| {
+                    let t = repo.get_template(id)?;
+                    let lang = audio::lang::detect(&t.text);
+                    let voice = repo.resolve_voice(None, lang.as_deref(), user_id)?;
+                    Ok((t, voice))
+                }).await?;
+
+            let values: Vec<(String, String)> = t.variables
+                .iter()
+                .map(|v| {
+                    let value = req.variables.remove(&v.name)
+                        .filter(|val| !val.trim().is_empty())
+                        .unwrap_or_else(|| v.value.clone());
+                    (v.name.clone(), value)
+                })
+                .collect();
+
+            let format = state.audio_format;
+            let audio_key = fs::template_audio_key(&t.text, &values, t.prompt_uri.as_deref(), &voice, format);
+
+            let uri = fs::template_audio_uri(id, &audio_key, format);
+            let cached = state.assets.is_audio_exist(&uri).await;
+            state.metrics.cache_hit(cached);
+            if cached {
+                return Ok(format!("{}/{}", urls::ASSETS, uri));
+            }
+
+            let prompt = match &t.prompt_uri {
+                Some(uri) => Some(tts::AudioPrompt {
+                    audio: state.assets.read_audio(uri).await?,
+                    variables: t.variables.clone(),
+                }),
+                None => None,
+            };
+
+            let audio = state.metrics
+                .time_synthesis(state.tts_client.synthesise_template(t.text, values, prompt, &voice, format)).await
+                .map_err(|err| AppError::Tts(format!("unable to synthesise template: {err}")))?;
+
+            let uri = state.assets.add_template_audio(id, &audio_key, format, audio).await?;
+
+            Ok(format!("{}/{}", urls::ASSETS, uri))
+        }
+
+        pub async fn update_sentence_voice(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::SetVoice>,
+        ) -> Result<(), AppError> {
+            state.db_client
+                .run(move |repo| {
+                    check_voice(repo, req.voice_id)?;
+                    repo.update_sentence_voice(id, req.voice_id)
+                }).await?;
+            Ok(())
+        }
+
+        pub async fn add_voice(
+            extract::State(state): extract::State<AppState>,
+            extract::Json(req): extract::Json<request::Voice>,
+        ) -> Result<String, AppError> {
+            let id = state.db_client
+                .run(move |repo| repo.add_voice(req.name, req.profile)).await?;
+            Ok(id.to_string())
+        }
+
+        pub async fn update_voice(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::Voice>,
+        ) -> Result<(), AppError> {
+            state.db_client
+                .run(move |repo| repo.update_voice(id, req.name, req.profile)).await?;
+            Ok(())
+        }
+
+        pub async fn drop_voice(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+        ) -> Result<(), AppError> {
+            state.db_client
+                .run(move |repo| repo.drop_voice(id)).await?;
+            Ok(())
+        }
+
+        /// Sets the language explicitly, without it the language is detected.
+        pub async fn update_sentence_lang(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(id): extract::Path<i32>,
+            extract::Json(req): extract::Json<request::SetLang>,
+        ) -> Result<(), AppError> {
+            check_lang(&req.lang)?;
+
+            state.db_client
+                .run(move |repo| {
+                    let lang = match req.lang {
+                        Some(lang) => Some(lang),
+                        None => audio::lang::detect(&repo.get_sentence(id)?.text),
+                    };
+                    repo.update_sentence_lang(id, lang)
+                }).await?;
+            Ok(())
+        }
+
+        pub async fn update_lang_voice(
+            extract::State(state): extract::State<AppState>,
+            extract::Path(lang): extract::Path<String>,
+            extract::Json(req): extract::Json<request::SetVoice>,
+        ) -> Result<(), AppError> {
+            check_lang(&Some(lang.clone()))?;
+
+            state.db_client
+                .run(move |repo| {
+                    check_voice(repo, req.voice_id)?;
+                    repo.update_lang_voice(lang, req.voice_id)
+                }).await?;
+            Ok(())
+        }
+
+        pub async fn update_user_voice(
+            extract::State(state): extract::State<AppState>,
+            UserId(user_id): UserId,
+            extract::Json(req): extract::Json<request::SetVoice>,
+        ) -> Result<(), AppError> {
+            state.db_client
+                .run(move |repo| {
+                    check_voice(repo, req.voice_id)?;
+                    repo.update_user_voice(user_id, req.voice_id)
+                }).await?;
+            Ok(())
+        }
+
+        /// A missing voice would fail voice resolution of every synthesis using the setting.
+        fn check_voice(repo: &impl Repository, voice_id: Option<i32>) -> Result<(), db::Error> {
+            if let Some(voice_id) = voice_id {
+                repo.get_voice(voice_id)?;
+            }
+            Ok(())
+        }
+
+        fn sentences_url(req: &request::ListSentences) -> String {
+            match serde_urlencoded::to_string(req) {
+                Ok(query) if !query.is_empty() => format!("{}?{query}", urls::SENTENCES),
+                _ => urls::SENTENCES.into(),
+            }
+        }
+
+        pub fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+            tags
+                .into_iter()
+                .map(|tag| tag.trim().to_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect()
+        }
+
+        pub fn check_lang(lang: &Option<String>) -> Result<(), AppError> {
+            match lang {
+                Some(lang) if !audio::lang::is_supported(lang) => {
+                    Err(AppError::BadRequest(format!("unsupported language '{lang}'")))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Keeps definitions of the variables used in `text`, new ones get empty defaults.
+        fn template_variables(text: &str, mut defined: Vec<tts::TemplateVar>) -> Vec<tts::TemplateVar> {
+            audio::text::variables(text)
+                .into_iter()
+                .map(|name| match defined.iter().position(|v| v.name == name) {
+                    Some(i) => defined.swap_remove(i),
+                    None => tts::TemplateVar { name, value: String::new(), start_ms: None, length_ms: None },
+                })
+                .collect()
+        }
+    }
+
+    mod mdlwr {
+        use std::net::SocketAddr;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        use axum::extract;
+        use axum::http::{header, HeaderValue, Request};
+        use axum::middleware::Next;
+        use axum::response::Response;
+        use tracing::{error, warn};
+
+        use crate::db::Repository;
+        use crate::http::token;
+        use crate::http::server::AppState;
+        use crate::http::server::error::AppError;
+        use crate::http::server::request::{ClientInfo, UserId};
+
+        pub const REQUEST_ID_HEADER: &str = "x-request-id";
+        const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+        const FORWARDED_PROTO_HEADER: &str = "x-forwarded-proto";
+
+        static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        pub async fn auth_layer<B>(
+            extract::State(state): extract::State<AppState>,
+            UserId(id): UserId,
+            request: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, AppError> {
+            if state.tg_valid_user_ids.contains(&id) {
+                return Ok(next.run(request).await);
+            }
+
+            error!("got user with invalid id='{}'", id);
+            Err(AppError::Unauthorized)
+        }
+
+        /// Must be layered under `auth_layer` which checks the cookie first.
+        pub async fn admin_layer<B>(
+            extract::State(state): extract::State<AppState>,
+            UserId(id): UserId,
+            request: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, AppError> {
+            if state.tg_root_user_ids.contains(&id) {
+                return Ok(next.run(request).await);
+            }
+
+            error!("got user id='{}' accessing admin endpoint", id);
+            Err(AppError::Forbidden)
+        }
+
+        /// Authorizes API requests by `Authorization: Bearer <token>`.
+        pub async fn token_layer<B>(
+            extract::State(state): extract::State<AppState>,
+            mut request: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, AppError> {
+            let token_hash = request.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(token::hash)
+                .ok_or(AppError::Unauthorized)?;
+
+            let user_id = state.db_client
+                .run(move |repo| repo.find_api_token_user(token_hash)).await?
+                .ok_or(AppError::Unauthorized)?;
+
+            if !state.tg_valid_user_ids.contains(&user_id) {
+                error!("got api token of user with invalid id='{}'", user_id);
+                return Err(AppError::Unauthorized);
+            }
+
+            request.extensions_mut().insert(UserId(user_id));
+            Ok(next.run(request).await)
+        }
+
+        /// Resolves the client address and scheme, requests of trusted proxies have them
+        /// in `X-Forwarded-For` and `X-Forwarded-Proto`, otherwise the server serves tls.
+        pub async fn client_layer<B>(
+            extract::State(state): extract::State<AppState>,
+            mut request: Request<B>,
+            next: Next<B>,
+        ) -> Response {
+            let peer = request.extensions()
+                .get::<extract::ConnectInfo<SocketAddr>>()
+                .map(|info| info.0.ip());
+            let header = |name: &str| request.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok());
+
+            let client = match (&state.trusted_proxies, peer) {
+                (Some(proxies), Some(peer)) if proxies.contains(peer) => ClientInfo {
+                    addr: header(FORWARDED_FOR_HEADER)
+                        .and_then(|value| proxies.forwarded_client(value))
+                        .or(Some(peer))
+                        .map(|addr| addr.to_string()),
+                    scheme: header(FORWARDED_PROTO_HEADER)
+                        .and_then(|value| value.split(',').next())
+                        .map(|value| value.trim().to_string())
+                        .filter(|value| !value.is_empty())
+                        .unwrap_or_else(|| "http".into()),
+                },
+                (Some(_), _) => ClientInfo { addr: peer.map(|addr| addr.to_string()), scheme: "http".into() },
+                (None, _) => ClientInfo { addr: peer.map(|addr| addr.to_string()), scheme: "https".into() },
+            };
+
+            request.extensions_mut().insert(client);
+            next.run(request).await
+        }
+
+        /// Tags the response with the request id, taken from the request or generated,
+        /// and renders `AppError` with it, so a client report can be matched with the logs.
+        pub async fn request_id_layer<B>(request: Request<B>, next: Next<B>) -> Response {
+            let request_id = request.headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .filter(|id| !id.is_empty() && id.len() <= 64)
+                .map(str::to_string)
+                .unwrap_or_else(new_request_id);
+            let method = request.method().clone();
+            let path = request.uri().path().to_string();
+            let client = request.extensions()
+                .get::<ClientInfo>()
+                .and_then(|client| client.addr.clone())
+                .unwrap_or_default();
+
+            let mut response = next.run(request).await;
+
+            if let Some(err) = response.extensions_mut().remove::<AppError>() {
+                match err.details() {
+                    Some(details) => error!(request_id, client, "{method} {path} failed: {details}"),
+                    None => warn!(request_id, client, "{method} {path} failed: {}", err.message()),
+                }
+                response = err.into_json(&request_id);
+            }
+
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            response
+        }
+
+        fn new_request_id() -> String {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            let n = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+            format!("{millis:x}-{n:x}")
+        }
+    }
+
+    mod error {
+        use axum::http::StatusCode;
+        use axum::Json;
+        use axum::response::{IntoResponse, Response};
+        use serde::Serialize;
+        use utoipa::ToSchema;
+
+        use crate::db;
+
+        /// Error of a handler. Messages of `BadRequest`, `NotFound` and `Conflict` are shown to clients,
+        /// details of `Tts` and `Internal` are only logged.
+        #[derive(Clone, Debug)]
+        pub enum AppError {
+            BadRequest(String),
+            Unauthorized,
+            Forbidden,
+            NotFound(String),
+            Conflict(String),
+            Tts(String),
+            Internal(String),
+        }
+
+        /// Body of the error responses, `error` is safe to show to users.
+        #[derive(Serialize, ToSchema)]
+        pub struct ErrorBody {
+            error: String,
+            request_id: String,
+        }
+
+        impl AppError {
+            pub fn status(&self) -> StatusCode {
+                match self {
+                    AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+                    AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+                    AppError::Forbidden => StatusCode::FORBIDDEN,
+                    AppError::NotFound(_) => StatusCode::NOT_FOUND,
+                    AppError::Conflict(_) => StatusCode::CONFLICT,
+                    AppError::Tts(_) => StatusCode::BAD_GATEWAY,
+                    AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            }
+
+            /// Message which is safe to show to clients.
+            pub fn message(&self) -> String {
+                match self {
+                    AppError::BadRequest(msg) | AppError::NotFound(msg) | AppError::Conflict(msg) => msg.clone(),
+                    AppError::Unauthorized => "unauthorized".into(),
+                    AppError::Forbidden => "forbidden".into(),
+                    AppError::Tts(_) => "speech synthesis is unavailable, try again later".into(),
+                    AppError::Internal(_) => "internal error".into(),
+                }
+            }
+
+            pub fn details(&self) -> Option<&str> {
+                match self {
+                    AppError::Tts(details) | AppError::Internal(details) => Some(details),
+                    _ => None,
+                }
+            }
+
+            pub fn into_json(self, request_id: &str) -> Response {
+                let body = ErrorBody { error: self.message(), request_id: request_id.into() };
+                (self.status(), Json(body)).into_response()
+            }
+        }
+
+        /// The body is rendered by `mdlwr::request_id_layer` which knows the request id.
+        impl IntoResponse for AppError {
+            fn into_response(self) -> Response {
+                let mut response = self.status().into_response();
+                response.extensions_mut().insert(self);
+                response
+            }
+        }
+
+        impl From<db::Error> for AppError {
+            fn from(err: db::Error) -> Self {
+                match err {
+                    db::Error::NotFound(msg) => AppError::NotFound(msg),
+                    db::Error::Conflict(msg) => AppError::Conflict(msg),
+                    db::Error::Internal(msg) => AppError::Internal(msg),
+                }
+            }
+        }
+
+        impl From<String> for AppError {
+            fn from(msg: String) -> Self {
+                AppError::Internal(msg)
+            }
+        }
+    }
+
+    /// Versioned JSON API for scripts, requests are authorized by tokens
+    /// issued with the `add-token` command.
+    mod api {
+        use axum::Router;
+        use axum::middleware;
+        use axum::routing::{get, post, put};
+
+        use crate::http::server::{AppState, mdlwr};
+
+        pub const PREFIX: &str = "/api/v1";
+
+        mod urls {
+            pub const OPENAPI: &str = "/openapi.json";
+            pub const SENTENCES: &str = "/sentences";
+            pub const SENTENCE: &str = "/sentences/:id";
+            pub const SENTENCE_VOICE: &str = "/sentences/:id/voice";
+            pub const PLAY_SENTENCE: &str = "/sentences/:id/play";
+            pub const JOBS: &str = "/jobs";
+            pub const JOB: &str = "/jobs/:id";
+            pub const VOICES: &str = "/voices";
+        }
+
+        mod request {
+            use serde::Deserialize;
+            use utoipa::{IntoParams, ToSchema};
+
+            #[derive(Deserialize, IntoParams, Debug)]
+            #[into_params(parameter_in = Query)]
+            pub struct ListSentences {
+                /// Full-text search query.
+                pub q: Option<String>,
+                pub tag: Option<String>,
+                /// `next_before` of the previous page.
+                pub before: Option<i32>,
+                pub limit: Option<u64>,
+            }
+
+            #[derive(Deserialize, ToSchema, Debug)]
+            pub struct AddSentence {
+                pub text: String,
+                /// Detected from the text if not set.
+                pub lang: Option<String>,
+                #[serde(default)]
+                pub tags: Vec<String>,
+            }
+
+            /// Only the set fields are changed.
+            #[derive(Deserialize, ToSchema, Debug)]
+            pub struct UpdateSentence {
+                pub text: Option<String>,
+                pub lang: Option<String>,
+                pub tags: Option<Vec<String>>,
+            }
+
+            #[derive(Deserialize, ToSchema, Debug)]
+            pub struct SetVoice {
+                /// Unsets the voice of the sentence if null.
+                pub voice_id: Option<i32>,
+            }
+        }
+
+        mod response {
+            use serde::Serialize;
+            use utoipa::ToSchema;
+
+            #[derive(Serialize, ToSchema)]
+            pub struct Sentence {
+                pub id: i32,
+                pub text: String,
+                pub lang: Option<String>,
+                pub voice_id: Option<i32>,
+                pub tags: Vec<String>,
+            }
+
+            impl From<crate::db::sqlite::Sentence> for Sentence {
+                fn from(s: crate::db::sqlite::Sentence) -> Self {
+                    Self {
+                        id: s.id,
+                        lang: s.lang.or_else(|| crate::audio::lang::detect(&s.text)),
+                        text: s.text,
+                        voice_id: s.voice_id,
+                        tags: s.tags,
+                    }
+                }
+            }
+
+            #[derive(Serialize, ToSchema)]
+            pub struct SentencePage {
+                pub sentences: Vec<Sentence>,
+                /// Pass as `before` to get the next page, null on the last page.
+                pub next_before: Option<i32>,
+            }
+
+            #[derive(Serialize, ToSchema)]
+            pub struct Voice {
+                pub id: i32,
+                pub name: String,
+                pub voice: String,
+                pub role: Option<String>,
+                pub speed: f64,
+                pub volume: Option<f64>,
+                pub pitch_shift: Option<f64>,
+            }
+
+            impl From<crate::db::sqlite::Voice> for Voice {
+                fn from(v: crate::db::sqlite::Voice) -> Self {
+                    Self {
+                        id: v.id,
+                        name: v.name,
+                        voice: v.profile.voice,
+                        role: v.profile.role,
+                        speed: v.profile.speed,
+                        volume: v.profile.volume,
+                        pitch_shift: v.profile.pitch_shift,
+                    }
+                }
+            }
+
+            #[derive(Serialize, ToSchema)]
+            pub struct Playback {
+                pub url: String,
+            }
+        }
+
+        mod handlers {
+            use axum::{extract, Json};
+            use axum::http::{header, StatusCode};
+            use axum::response::{IntoResponse, Response};
+            use utoipa::OpenApi;
+
+            use crate::{audio, db};
+            use crate::db::Repository;
+            use crate::http::server::{AppState, handlers as web};
+            use crate::http::server::api::{doc, request, response, urls, PREFIX};
+            use crate::http::server::error::AppError;
+            use crate::http::server::request::UserId;
+            use crate::http::server::response::{Event, Job};
+
+            const DEFAULT_PAGE_SIZE: u64 = 50;
+            const MAX_PAGE_SIZE: u64 = 500;
+
+            pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+                Json(doc::ApiDoc::openapi())
+            }
+
+            /// Lists sentences, newest first.
+            #[utoipa::path(
+                get,
+                path = "/sentences",
+                tag = "sentences",
+                params(request::ListSentences),
+                responses(
+                    (status = 200, body = SentencePage),
+                    (status = 401, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn list_sentences(
+                extract::State(state): extract::State<AppState>,
+                extract::Query(req): extract::Query<request::ListSentences>,
+            ) -> Result<Json<response::SentencePage>, AppError> {
+                let filter = db::sqlite::SentenceFilter {
+                    text: req.q,
+                    tag: req.tag,
+                    before_id: req.before,
+                    limit: req.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+                };
+
+                let page = state.db_client
+                    .run(move |repo| repo.list_sentences(filter)).await?;
+
+                Ok(Json(response::SentencePage {
+                    sentences: page.sentences.into_iter().map(response::Sentence::from).collect(),
+                    next_before: page.next_before_id,
+                }))
+            }
+
+            /// Adds the sentence and queues synthesis of its audio.
+            #[utoipa::path(
+                post,
+                path = "/sentences",
+                tag = "sentences",
+                request_body = AddSentence,
+                responses(
+                    (status = 201, body = Sentence),
+                    (status = 400, body = ErrorBody),
+                    (status = 401, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn add_sentence(
+                extract::State(state): extract::State<AppState>,
+                UserId(user_id): UserId,
+                extract::Json(req): extract::Json<request::AddSentence>,
+            ) -> Result<Response, AppError> {
+                if req.text.trim().is_empty() {
+                    return Err(AppError::BadRequest("text must not be empty".into()));
+                }
+                web::check_lang(&req.lang)?;
+
+                let sentence = db::sqlite::NewSentence {
+                    lang: req.lang.or_else(|| audio::lang::detect(&req.text)),
+                    text: req.text,
+                    tags: web::normalize_tags(req.tags),
+                };
+
+                let s = state.db_client
+                    .run(move |repo| {
+                        let ids = repo.import_sentences(vec![sentence])?;
+                        repo.get_sentence(ids[0])
+                    }).await?;
+                state.publish(Event::SentenceAdded { id: s.id });
+
+                web::enqueue_synthesis(&state, vec![s.id], user_id).await?;
+
+                let location = format!("{PREFIX}{}/{}", urls::SENTENCES, s.id);
+                Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(response::Sentence::from(s))).into_response())
+            }
+
+            #[utoipa::path(
+                get,
+                path = "/sentences/{id}",
+                tag = "sentences",
+                params(("id" = i32, Path, description = "Sentence id")),
+                responses(
+                    (status = 200, body = Sentence),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn get_sentence(
+                extract::State(state): extract::State<AppState>,
+                extract::Path(id): extract::Path<i32>,
+            ) -> Result<Json<response::Sentence>, AppError> {
+                let s = state.db_client
+                    .run(move |repo| repo.get_sentence(id)).await?;
+
+                Ok(Json(response::Sentence::from(s)))
+            }
+
+            /// Changing the text drops the cached audio of the sentence.
+            #[utoipa::path(
+                patch,
+                path = "/sentences/{id}",
+                tag = "sentences",
+                params(("id" = i32, Path, description = "Sentence id")),
+                request_body = UpdateSentence,
+                responses(
+                    (status = 200, body = Sentence),
+                    (status = 400, body = ErrorBody),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn update_sentence(
+                extract::State(state): extract::State<AppState>,
+                extract::Path(id): extract::Path<i32>,
+                extract::Json(req): extract::Json<request::UpdateSentence>,
+            ) -> Result<Json<response::Sentence>, AppError> {
+                web::check_lang(&req.lang)?;
+                let update = db::sqlite::SentenceUpdate {
+                    lang: match &req.text {
+                        Some(text) => req.lang.or_else(|| audio::lang::detect(text)),
+                        None => req.lang,
+                    },
+                    text: req.text,
+                    tags: req.tags.map(web::normalize_tags),
+                };
+
+                let (s, orphan) = state.db_client
+                    .run(move |repo| {
+                        let orphan = repo.update_sentence(id, update)?;
+                        Ok((repo.get_sentence(id)?, orphan))
+                    }).await?;
+
+                if let Some(uri) = orphan {
+                    state.assets.drop_audio(&uri).await?;
+                }
+
+                Ok(Json(response::Sentence::from(s)))
+            }
+
+            #[utoipa::path(
+                delete,
+                path = "/sentences/{id}",
+                tag = "sentences",
+                params(("id" = i32, Path, description = "Sentence id")),
+                responses(
+                    (status = 204),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn drop_sentence(
+                extract::State(state): extract::State<AppState>,
+                extract::Path(id): extract::Path<i32>,
+            ) -> Result<StatusCode, AppError> {
+                web::remove_sentence(&state, id).await?;
+
+                Ok(StatusCode::NO_CONTENT)
+            }
+
+            #[utoipa::path(
+                put,
+                path = "/sentences/{id}/voice",
+                tag = "sentences",
+                params(("id" = i32, Path, description = "Sentence id")),
+                request_body = SetVoice,
+                responses(
+                    (status = 200, body = Sentence),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn update_sentence_voice(
+                extract::State(state): extract::State<AppState>,
+                extract::Path(id): extract::Path<i32>,
+                extract::Json(req): extract::Json<request::SetVoice>,
+            ) -> Result<Json<response::Sentence>, AppError> {
+                let s = state.db_client
+                    .run(move |repo| {
+                        repo.get_sentence(id)?;
+                        if let Some(voice_id) = req.voice_id {
+                            repo.get_voice(voice_id)?;
+                        }
+                        repo.update_sentence_voice(id, req.voice_id)?;
+                        repo.get_sentence(id)
+                    }).await?;
+
+                Ok(Json(response::Sentence::from(s)))
+            }
+
+            /// Returns the audio url if it's ready, otherwise queues synthesis
+            /// and returns the job to poll.
+            #[utoipa::path(
+                post,
+                path = "/sentences/{id}/play",
+                tag = "playback",
+                params(("id" = i32, Path, description = "Sentence id")),
+                responses(
+                    (status = 200, body = Playback),
+                    (status = 202, body = Job, headers(("Location" = String, description = "Url of the job"))),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn play_sentence(
+                extract::State(state): extract::State<AppState>,
+                UserId(user_id): UserId,
+                extract::Path(id): extract::Path<i32>,
+            ) -> Result<Response, AppError> {
+                if let Some(uri) = web::sentence_audio(&state, id, user_id.clone(), false).await? {
+                    return Ok(Json(response::Playback { url: web::asset_url(&uri) }).into_response());
+                }
+
+                let job_ids = web::enqueue_synthesis(&state, vec![id], user_id).await?;
+                let job_id = job_ids[0];
+                let job = state.db_client
+                    .run(move |repo| repo.get_job(job_id)).await?;
+
+                let location = format!("{PREFIX}{}/{job_id}", urls::JOBS);
+                Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(Job::from(job))).into_response())
+            }
+
+            #[utoipa::path(
+                get,
+                path = "/jobs/{id}",
+                tag = "playback",
+                params(("id" = i32, Path, description = "Job id")),
+                responses(
+                    (status = 200, body = Job),
+                    (status = 401, body = ErrorBody),
+                    (status = 404, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn job(
+                extract::State(state): extract::State<AppState>,
+                extract::Path(id): extract::Path<i32>,
+            ) -> Result<Json<Job>, AppError> {
+                let job = state.db_client
+                    .run(move |repo| repo.get_job(id)).await?;
+
+                Ok(Json(Job::from(job)))
+            }
+
+            #[utoipa::path(
+                get,
+                path = "/voices",
+                tag = "voices",
+                responses(
+                    (status = 200, body = [Voice]),
+                    (status = 401, body = ErrorBody),
+                ),
+                security(("token" = [])),
+            )]
+            pub async fn voices(
+                extract::State(state): extract::State<AppState>,
+            ) -> Result<Json<Vec<response::Voice>>, AppError> {
+                let voices = state.db_client
+                    .run(|repo| repo.list_voices()).await?;
+
+                Ok(Json(voices.into_iter().map(response::Voice::from).collect()))
+            }
+        }
+
+        mod doc {
+            use utoipa::{Modify, OpenApi};
+            use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+            use crate::http::server::api::{handlers, request, response};
+            use crate::http::server::error::ErrorBody;
+            use crate::http::server::response::Job;
+
+            #[derive(OpenApi)]
+            #[openapi(
+                info(title = "read4me", description = "Sentences library with synthesised audio."),
+                servers((url = "/api/v1")),
+                paths(
+                    handlers::list_sentences,
+                    handlers::add_sentence,
+                    handlers::get_sentence,
+                    handlers::update_sentence,
+                    handlers::drop_sentence,
+                    handlers::update_sentence_voice,
+                    handlers::play_sentence,
+                    handlers::job,
+                    handlers::voices,
+                ),
+                components(schemas(
+                    request::AddSentence,
+                    request::UpdateSentence,
+                    request::SetVoice,
+                    response::Sentence,
+                    response::SentencePage,
+                    response::Voice,
+                    response::Playback,
+                    Job,
+                    ErrorBody,
+                )),
+                modifiers(&TokenAuth),
+            )]
+            pub struct ApiDoc;
+
+            struct TokenAuth;
+
+            impl Modify for TokenAuth {
+                fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+                    let components = openapi.components.get_or_insert_with(Default::default);
+                    components.add_security_scheme(
+                        "token",
+                        SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+                    );
+                }
+            }
+        }
+
+        /// The document is public, other routes require a token.
+        pub fn router(state: AppState) -> Router<AppState> {
+            let token_middleware = middleware::from_fn_with_state(
+                state,
+                mdlwr::token_layer,
+            );
+
+            Router::new()
+                .route(urls::SENTENCES, get(handlers::list_sentences).post(handlers::add_sentence))
+                .route(urls::SENTENCE, get(handlers::get_sentence)
+                    .patch(handlers::update_sentence)
+                    .delete(handlers::drop_sentence),
+                )
+                .route(urls::SENTENCE_VOICE, put(handlers::update_sentence_voice))
+                .route(urls::PLAY_SENTENCE, post(handlers::play_sentence))
+                .route(urls::JOB, get(handlers::job))
+                .route(urls::VOICES, get(handlers::voices))
+                .route_layer(token_middleware)
+                .route(urls::OPENAPI, get(handlers::openapi))
+        }
+    }
+
+    pub struct Config {
+        pub db_client: db::sqlite::Client,
+        pub tts_client: Arc<dyn SpeechSynthesizer>,
+        pub assets: fs::Assets,
+        /// Shared with the telegram bot which adds sentences as well.
+        pub events: broadcast::Sender<response::Event>,
+        pub audio_format: audio::Format,
+        pub tg_valid_user_ids: Vec<String>,
+        pub tg_root_user_ids: Vec<String>,
+        pub synthesis_workers: usize,
+        pub address: String,
+        pub listener: servekit::Listener,
+        pub shutdown: CancellationToken,
+        pub metrics: Metrics,
+    }
+
+    /// Sender of the library changes, events without subscribers are dropped.
+    pub fn events() -> broadcast::Sender<response::Event> {
+        broadcast::channel(EVENTS_CAPACITY).0
+    }
+
+    pub async fn init(cfg: Config) {
+        let state = AppState {
+            db_client: cfg.db_client,
+            tts_client: cfg.tts_client,
+            assets: cfg.assets,
+            audio_format: cfg.audio_format,
+            tg_valid_user_ids: Arc::new(cfg.tg_valid_user_ids),
+            tg_root_user_ids: Arc::new(cfg.tg_root_user_ids),
+            jobs: Arc::new(Notify::new()),
+            events: cfg.events,
+            trusted_proxies: match &cfg.listener {
+                servekit::Listener::Http(proxies) => Some(proxies.clone()),
+                _ => None,
+            },
+            shutdown: cfg.shutdown.clone(),
+            metrics: cfg.metrics,
+        };
+
+        let requeued = state.db_client
+            .run(|repo| repo.requeue_running_jobs()).await
+            .expect("unable to requeue synthesis jobs");
+        if requeued > 0 {
+            info!("requeued {requeued} interrupted synthesis jobs");
+        }
+
+        let workers: Vec<_> = (0..cfg.synthesis_workers)
+            .map(|_| tokio::spawn(run_synthesis_worker(state.clone())))
+            .collect();
+        tokio::spawn(clean_assets_periodically(state.clone()));
+
+        let address = cfg.address.parse().expect("invalid address");
+        servekit::serve(address, cfg.listener, router(state), cfg.shutdown).await
+            .expect("unable to run web server");
+
+        // running jobs are requeued on the next start if they don't make it
+        let deadline = tokio::time::Instant::now() + servekit::SHUTDOWN_TIMEOUT;
+        for worker in workers {
+            if tokio::time::timeout_at(deadline, worker).await.is_err() {
+                error!("synthesis workers haven't stopped in time");
+                break;
+            }
+        }
+    }
+
+    async fn clean_assets_periodically(state: AppState) {
+        let mut interval = tokio::time::interval(ASSETS_CLEANUP_PERIOD);
+        loop {
+            interval.tick().await;
+
+            match clean_assets(&state, ASSETS_CLEANUP_GRACE, TEMPLATE_AUDIO_TTL).await {
+                Ok(removed) if !removed.is_empty() => info!("removed orphaned assets: {removed:?}"),
+                Ok(_) => {}
+                Err(err) => error!("unable to clean assets: {err}"),
+            }
+
+            let before = chrono::Utc::now() - JOBS_RETENTION;
+            match state.db_client.run(move |repo| repo.drop_finished_jobs(before)).await {
+                Ok(removed) if removed > 0 => info!("removed {removed} finished synthesis jobs"),
+                Ok(_) => {}
+                Err(err) => error!("unable to clean synthesis jobs: {err}"),
+            }
+        }
+    }
+
+    /// Workers share the tts rate limit, their number bounds concurrent synthesis of jobs.
+    /// The job in progress is finished on shutdown.
+    async fn run_synthesis_worker(state: AppState) {
+        while !state.shutdown.is_cancelled() {
+            match run_next_job(&state).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(err) => error!("unable to run synthesis job: {err}"),
+            }
+
+            // notifications may be missed while the worker is busy, so the queue is polled as well
+            tokio::select! {
+                _ = state.shutdown.cancelled() => {}
+                _ = tokio::time::timeout(JOBS_POLL_PERIOD, state.jobs.notified()) => {}
+            }
+        }
+    }
+
+    /// Returns `false` if there are no due jobs. A failed job is queued
+    /// again with a backoff until it runs out of attempts.
+    async fn run_next_job(state: &AppState) -> Result<bool, String> {
+        let Some(job) = state.db_client.run(|repo| repo.take_job()).await? else {
+            return Ok(false);
+        };
+
+        let res = handlers::sentence_audio(state, job.sentence_id, job.user_id, true).await;
+        let (status, uri, err) = match res {
+            Ok(uri) => (db::sqlite::JobStatus::Done, uri, None),
+            Err(err) => {
+                error!(
+                    "unable to synthesise sentence id='{}': {}",
+                    job.sentence_id, err.details().unwrap_or(&err.message()),
+                );
+                if job.attempts < SYNTHESIS_ATTEMPTS {
+                    let not_before = chrono::Utc::now() + retry_backoff(job.attempts);
+                    state.db_client
+                        .run(move |repo| repo.retry_job(job.id, err.message(), not_before)).await?;
+                    return Ok(true);
+                }
+                (db::sqlite::JobStatus::Failed, None, Some(err.message()))
+            }
+        };
+
+        let event = match (status, &uri, &err) {
+            (db::sqlite::JobStatus::Done, Some(uri), _) => Some(response::Event::AudioReady {
+                sentence_id: job.sentence_id,
+                job_id: job.id,
+                url: handlers::asset_url(uri),
+            }),
+            (db::sqlite::JobStatus::Failed, _, Some(err)) => Some(response::Event::SynthesisFailed {
+                sentence_id: job.sentence_id,
+                job_id: job.id,
+                error: err.clone(),
+            }),
+            _ => None,
+        };
+
+        state.db_client
+            .run(move |repo| repo.update_job(job.id, status, uri, err)).await?;
+
+        if let Some(event) = event {
+            state.publish(event);
+        }
+
+        Ok(true)
+    }
+
+    fn retry_backoff(attempts: i32) -> Duration {
+        SYNTHESIS_RETRY_BACKOFF * 2u32.pow(attempts.saturating_sub(1) as u32)
+    }
+
+    /// Removes audio files which aren't referenced by sentences, prompts or templates.
+    /// Renders of templates are removed once older than `template_ttl`.
+    async fn clean_assets(state: &AppState, grace: Duration, template_ttl: Duration) -> Result<Vec<String>, String> {
+        let (uris, templates) = state.db_client
+            .run(|repo| Ok((repo.list_audio_uris()?, repo.list_templates()?)))
+            .await?;
+
+        let uris: HashSet<String> = uris.into_iter().collect();
+        let prefixes: Vec<String> = templates
+            .iter()
+            .map(|t| fs::template_audio_prefix(t.id))
+            .collect();
+
+        state.assets
+            .remove_orphans(grace, |name, age| {
+                uris.contains(name)
+                    || (age < template_ttl && prefixes.iter().any(|prefix| name.starts_with(prefix)))
+            })
+            .await
+    }
+
+    fn router(state: AppState) -> Router {
+        let auth_middleware = middleware::from_fn_with_state(
+            state.clone(),
+            mdlwr::auth_layer,
+        );
+        let admin_middleware = middleware::from_fn_with_state(
+            state.clone(),
+            mdlwr::admin_layer,
+        );
+
+        Router::new()
+            .route(urls::ROOT, get(handlers::root))
+            .route(urls::HEALTHZ, get(servekit::healthz))
+            .route(urls::READYZ, get(handlers::readyz))
+            .route(urls::METRICS, get(handlers::metrics))
+            .route(urls::AUTH, post(handlers::auth))
+            .route(urls::SENTENCES, get(handlers::sentences)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::ADD_SENTENCE, post(handlers::add_sentence)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::DROP_SENTENCE, delete(handlers::drop_sentence)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::UPDATE_SENTENCE, patch(handlers::update_sentence)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::SENTENCE_HISTORY, get(handlers::sentence_history)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::REVERT_SENTENCE, post(handlers::revert_sentence)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::PLAY_SENTENCE, post(handlers::play_sentence)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::JOB, get(handlers::job)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::EVENTS, get(handlers::events)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::IMPORT_SENTENCES, post(handlers::import_sentences)
+                .route_layer(admin_middleware.clone())
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::EXPORT_SENTENCES, get(handlers::export_sentences)
+                .route_layer(admin_middleware)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::SENTENCE_TAGS, put(handlers::update_sentence_tags)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::SENTENCE_LANG, put(handlers::update_sentence_lang)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::LANG_VOICE, put(handlers::update_lang_voice)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::SENTENCE_VOICE, put(handlers::update_sentence_voice)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::ADD_VOICE, post(handlers::add_voice)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::VOICE, put(handlers::update_voice)
+                .delete(handlers::drop_voice)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::USER_VOICE, put(handlers::update_user_voice)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::ADD_TEMPLATE, post(handlers::add_template)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::TEMPLATE, put(handlers::update_template)
+                .delete(handlers::drop_template)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::TEMPLATE_PROMPT, put(handlers::update_template_prompt)
+                .delete(handlers::drop_template_prompt)
+                .route_layer(auth_middleware.clone()),
+            )
+            .route(urls::PLAY_TEMPLATE, post(handlers::play_template)
+                .route_layer(auth_middleware),
+            )
+            .nest(api::PREFIX, api::router(state.clone()))
+            .nest_service(urls::ASSETS, state.assets.serve_dir())
+            .layer(middleware::from_fn(mdlwr::request_id_layer))
+            .layer(middleware::from_fn_with_state(state.clone(), mdlwr::client_layer))
+            .with_state(state)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::net::SocketAddr;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use axum::body::Body;
+        use axum::extract::ConnectInfo;
+        use axum::http::{header, Method, Request, StatusCode};
+        use axum::Router;
+        use hyper::body::HttpBody;
+        use tower::ServiceExt;
+
+        use crate::{audio, db};
+        use crate::db::Repository;
+        use crate::http::{fs, token};
+        use crate::http::server::{AppState, clean_assets, router, run_next_job};
+        use crate::metrics::Metrics;
+        use crate::rpc::local;
+
+        const USER_ID: &str = "42";
+
+        async fn app(name: &str) -> Router {
+            router(state(name).await)
+        }
+
+        async fn state(name: &str) -> AppState {
+            let dir = std::env::temp_dir().join(format!("read4me_{name}_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            AppState {
+                db_client: db::sqlite::Client::new(dir.join("read4me.db").to_str().unwrap()),
+                tts_client: Arc::new(local::Synthesizer::new(local::Sound::Tone)),
+                assets: fs::Assets::new(dir.join("assets").to_str().unwrap()).await,
+                audio_format: audio::Format::Wav,
+                tg_valid_user_ids: Arc::new(vec![USER_ID.into()]),
+                tg_root_user_ids: Arc::new(vec![USER_ID.into()]),
+                jobs: Arc::new(tokio::sync::Notify::new()),
+                events: tokio::sync::broadcast::channel(16).0,
+                trusted_proxies: None,
+                shutdown: tokio_util::sync::CancellationToken::new(),
+                metrics: Metrics::new(),
+            }
+        }
+
+        /// Plays the sentence running queued jobs in place of the workers, returns url of the audio.
+        async fn play(state: &AppState, app: &Router, id: &str) -> String {
+            let play_url = format!("/sentences/{id}/play");
+            let (mut status, mut url) = call(app, Method::POST, &play_url, None).await;
+            if status == StatusCode::ACCEPTED {
+                while run_next_job(state).await.unwrap() {}
+                (status, url) = call(app, Method::POST, &play_url, None).await;
+            }
+            assert_eq!(status, StatusCode::OK);
+
+            String::from_utf8(url).unwrap()
+        }
+
+        async fn call(app: &Router, method: Method, uri: &str, body: Option<&str>) -> (StatusCode, Vec<u8>) {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::COOKIE, format!("id={USER_ID}"));
+            if body.is_some() {
+                req = req.header(header::CONTENT_TYPE, "application/json");
+            }
+            let req = req.body(Body::from(body.unwrap_or_default().to_string())).unwrap();
+
+            let resp = app.clone().oneshot(req).await.unwrap();
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+            (status, body.to_vec())
+        }
+
+        #[tokio::test]
+        async fn play_sentence() {
+            let state = state("play_sentence").await;
+            let app = router(state.clone());
+
+            let (status, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+            assert_eq!(status, StatusCode::OK);
+            let id = String::from_utf8(id).unwrap();
+
+            let play_url = format!("/sentences/{id}/play");
+            let (status, job_url) = call(&app, Method::POST, &play_url, None).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            let job_url = String::from_utf8(job_url).unwrap();
+            assert_eq!(job_url, "/jobs/1");
+
+            let (_, job) = call(&app, Method::GET, &job_url, None).await;
+            let job: serde_json::Value = serde_json::from_slice(&job).unwrap();
+            assert_eq!(job["status"], "queued");
+
+            assert!(run_next_job(&state).await.unwrap());
+            assert!(!run_next_job(&state).await.unwrap());
+
+            let (_, job) = call(&app, Method::GET, &job_url, None).await;
+            let job: serde_json::Value = serde_json::from_slice(&job).unwrap();
+            assert_eq!(job["status"], "done");
+
+            let (status, url) = call(&app, Method::POST, &play_url, None).await;
+            assert_eq!(status, StatusCode::OK);
+            let url = String::from_utf8(url).unwrap();
+            assert_eq!(job["url"], url.as_str());
+            assert!(url.starts_with("/assets/") && url.ends_with(".wav"));
+
+            let (status, audio) = call(&app, Method::GET, &url, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(&audio[0..4], b"RIFF");
+            assert!(audio.len() > 44);
+
+            let (_, cached_url) = call(&app, Method::POST, &play_url, None).await;
+            assert_eq!(String::from_utf8(cached_url).unwrap(), url);
+        }
+
+        #[tokio::test]
+        async fn play_template() {
+            let app = app("play_template").await;
+
+            let (status, id) = call(&app, Method::POST, "/templates", Some(r#"{"name": "hi", "text": "Hi {name}"}"#)).await;
+            assert_eq!(status, StatusCode::OK);
+            let id = String::from_utf8(id).unwrap();
+
+            let play_url = format!("/templates/{id}/play");
+            let (status, short) = call(&app, Method::POST, &play_url, Some(r#"{"variables": {"name": "Bo"}}"#)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, long) = call(&app, Method::POST, &play_url, Some(r#"{"variables": {"name": "Bartholomew"}}"#)).await;
+            assert_ne!(short, long);
+
+            let (_, short) = call(&app, Method::GET, &String::from_utf8(short).unwrap(), None).await;
+            let (_, long) = call(&app, Method::GET, &String::from_utf8(long).unwrap(), None).await;
+            assert!(short.len() < long.len());
+
+            let (status, _) = call(&app, Method::PUT, "/templates/99", Some(r#"{"name": "hi", "text": "Hi", "variables": []}"#)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let (status, _) = call(&app, Method::PUT, "/templates/99/prompt", Some("RIFF")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (status, _) = call(&app, Method::DELETE, &format!("/templates/{id}"), None).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = call(&app, Method::DELETE, &format!("/templates/{id}"), None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn share_audio_between_sentences() {
+            let state = state("share_audio_between_sentences").await;
+            let app = router(state.clone());
+
+            let mut urls = Vec::new();
+            for _ in 0..2 {
+                let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+                let id = String::from_utf8(id).unwrap();
+                let url = play(&state, &app, &id).await;
+                urls.push((id, url));
+            }
+            assert_eq!(urls[0].1, urls[1].1);
+
+            let (status, _) = call(&app, Method::DELETE, &format!("/sentences/{}", urls[0].0), None).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = call(&app, Method::GET, &urls[1].1, None).await;
+            assert_eq!(status, StatusCode::OK);
+
+            call(&app, Method::DELETE, &format!("/sentences/{}", urls[1].0), None).await;
+            let (status, _) = call(&app, Method::GET, &urls[1].1, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn remove_orphaned_assets() {
+            let state = state("remove_orphaned_assets").await;
+            let app = router(state.clone());
+
+            let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+            let id = String::from_utf8(id).unwrap();
+            let url = play(&state, &app, &id).await;
+            let uri = url.trim_start_matches("/assets/").to_string();
+
+            state.assets.add_audio("orphan", audio::Format::Wav, vec![0]).await.unwrap();
+            let voice_uri = state.assets.add_audio("voice", audio::Format::OggOpus, vec![0]).await.unwrap();
+            state.db_client
+                .run({
+                    let uri = voice_uri.clone();
+                    move |repo| repo.update_tg_voice("voice".into(), "file_id".into(), uri)
+                })
+                .await
+                .unwrap();
+
+            let (_, template_id) = call(&app, Method::POST, "/templates", Some(r#"{"name": "hi", "text": "Hi"}"#)).await;
+            let template_id: i32 = String::from_utf8(template_id).unwrap().parse().unwrap();
+            let render = state.assets
+                .add_template_audio(template_id, "render", audio::Format::Wav, vec![0]).await
+                .unwrap();
+
+            let removed = clean_assets(&state, Duration::ZERO, Duration::from_secs(60)).await.unwrap();
+
+            assert_eq!(removed, vec!["orphan.wav".to_string()]);
+            assert!(state.assets.is_audio_exist(&uri).await);
+            assert!(state.assets.is_audio_exist(&voice_uri).await);
+
+            let removed = clean_assets(&state, Duration::ZERO, Duration::ZERO).await.unwrap();
+            assert_eq!(removed, vec![render]);
+        }
+
+        #[tokio::test]
+        async fn edit_and_revert_sentence() {
+            let state = state("edit_and_revert_sentence").await;
+            let app = router(state.clone());
+
+            let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Превет"}"#)).await;
+            let id = String::from_utf8(id).unwrap();
+            let url = play(&state, &app, &id).await;
+
+            let (status, _) = call(&app, Method::PATCH, &format!("/sentences/{id}"), Some(r#"{"text": "Привет"}"#)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = call(&app, Method::GET, &url, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (_, history) = call(&app, Method::GET, &format!("/sentences/{id}/history"), None).await;
+            let history: serde_json::Value = serde_json::from_slice(&history).unwrap();
+            assert_eq!(history[0]["text"], "Превет");
+
+            let edit_id = &history[0]["id"];
+            let (status, _) = call(&app, Method::POST, &format!("/sentences/{id}/history/{edit_id}/revert"), None).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (_, history) = call(&app, Method::GET, &format!("/sentences/{id}/history"), None).await;
+            let history: serde_json::Value = serde_json::from_slice(&history).unwrap();
+            assert_eq!(history.as_array().unwrap().len(), 2);
+            assert_eq!(history[0]["text"], "Привет");
+
+            assert_eq!(play(&state, &app, &id).await, url);
+        }
+
+        #[tokio::test]
+        async fn search_sentences() {
+            let app = app("search_sentences").await;
+
+            for text in ["Привет, мир", "Пока, мир", "Доброе утро"] {
+                call(&app, Method::POST, "/sentences", Some(&format!(r#"{{"text": "{text}"}}"#))).await;
+            }
+            call(&app, Method::PUT, "/sentences/3/tags", Some(r#"{"tags": [" Morning ", ""]}"#)).await;
+            let (status, _) = call(&app, Method::PUT, "/sentences/99/tags", Some(r#"{"tags": ["night"]}"#)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (status, page) = call(&app, Method::GET, "/sentences?q=%D0%BC%D0%B8", None).await;
+            assert_eq!(status, StatusCode::OK);
+            let page = String::from_utf8(page).unwrap();
+            assert!(page.contains("Привет, мир") && page.contains("Пока, мир") && !page.contains("Доброе утро"));
+
+            let (_, page) = call(&app, Method::GET, "/sentences?tag=morning", None).await;
+            let page = String::from_utf8(page).unwrap();
+            assert!(page.contains("Доброе утро") && !page.contains("Пока, мир"));
+
+            let (_, page) = call(&app, Method::GET, "/sentences?before=3", None).await;
+            let page = String::from_utf8(page).unwrap();
+            assert!(page.contains("Пока, мир") && !page.contains("Доброе утро"));
+        }
+
+        #[tokio::test]
+        async fn set_sentence_language() {
+            let app = app("set_sentence_language").await;
+
+            let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Good morning, how did you sleep?"}"#)).await;
+            let id = String::from_utf8(id).unwrap();
+            let (_, page) = call(&app, Method::GET, "/sentences", None).await;
+            assert!(String::from_utf8(page).unwrap().contains(r#"<option value="en" selected>"#));
+
+            let (status, _) = call(&app, Method::PUT, &format!("/sentences/{id}/lang"), Some(r#"{"lang": "de"}"#)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, page) = call(&app, Method::GET, "/sentences", None).await;
+            assert!(String::from_utf8(page).unwrap().contains(r#"<option value="de" selected>"#));
+
+            let body = r#"{"text": "Good morning, how did you sleep?", "lang": "kk"}"#;
+            let (status, _) = call(&app, Method::PATCH, &format!("/sentences/{id}"), Some(body)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, page) = call(&app, Method::GET, "/sentences", None).await;
+            assert!(String::from_utf8(page).unwrap().contains(r#"<option value="kk" selected>"#));
+
+            let (status, _) = call(&app, Method::PUT, &format!("/sentences/{id}/lang"), Some(r#"{"lang": "xx"}"#)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (status, _) = call(&app, Method::PUT, "/sentences/99/lang", Some(r#"{"lang": "de"}"#)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn add_and_update_voices() {
+            let app = app("add_and_update_voices").await;
+
+            let voice = r#"{"name": "calm", "voice": "alena", "speed": 1.0}"#;
+            let (status, id) = call(&app, Method::POST, "/voices", Some(voice)).await;
+            assert_eq!(status, StatusCode::OK);
+            let id = String::from_utf8(id).unwrap();
+
+            let (status, _) = call(&app, Method::POST, "/voices", Some(voice)).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+
+            let (status, _) = call(&app, Method::PUT, &format!("/voices/{id}"), Some(voice)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = call(&app, Method::PUT, "/voices/99", Some(voice)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let body = format!(r#"{{"voice_id": {id}}}"#);
+            let (status, _) = call(&app, Method::PUT, "/sentences/99/voice", Some(&body)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            let (_, sentence_id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+            let sentence_id = String::from_utf8(sentence_id).unwrap();
+            let missing = r#"{"voice_id": 99}"#;
+            for url in [&format!("/sentences/{sentence_id}/voice"), "/settings/langs/ru/voice", "/settings/voice"] {
+                let (status, _) = call(&app, Method::PUT, url, Some(missing)).await;
+                assert_eq!(status, StatusCode::NOT_FOUND, "{url}");
+            }
+
+            let (status, _) = call(&app, Method::DELETE, &format!("/voices/{id}"), None).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = call(&app, Method::DELETE, &format!("/voices/{id}"), None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn import_and_export_sentences() {
+            let mut state = state("import_and_export_sentences").await;
+            let app = router(state.clone());
+
+            let text = "Доброе утро #morning\n\nGood night #evening #EN\n";
+            let (status, ids) = call(&app, Method::POST, "/sentences/import?format=text", Some(text)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(ids, b"[1,2]");
+
+            let (status, csv) = call(&app, Method::GET, "/sentences/export?format=csv", None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                String::from_utf8(csv).unwrap(),
+                "text,lang,tags\nДоброе утро,ru,morning\nGood night,en,en;evening\n",
+            );
+
+            let (status, _) = call(&app, Method::POST, "/sentences/import?format=json", Some(r#"[{"text": "Hi", "lang": "xx"}]"#)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+
+            state.tg_root_user_ids = Arc::new(Vec::new());
+            let (status, _) = call(&router(state), Method::GET, "/sentences/export?format=text", None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+
+        #[tokio::test]
+        async fn stream_events() {
+            let state = state("stream_events").await;
+            let app = router(state.clone());
+
+            let req = Request::builder()
+                .uri("/events")
+                .header(header::COOKIE, format!("id={USER_ID}"))
+                .body(Body::empty())
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+            let mut events = resp.into_body();
+
+            let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+            let id = String::from_utf8(id).unwrap();
+            run_next_job(&state).await.unwrap();
+            call(&app, Method::DELETE, &format!("/sentences/{id}"), None).await;
+
+            let mut data = String::new();
+            while let Some(chunk) = events.data().await {
+                data.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+                if data.contains("sentence_removed") {
+                    break;
+                }
+            }
+
+            let types: Vec<String> = data
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap()["type"].to_string())
+                .collect();
+            assert_eq!(types, [r#""sentence_added""#, r#""audio_ready""#, r#""sentence_removed""#]);
+
+            state.shutdown.cancel();
+            let rest = tokio::time::timeout(Duration::from_secs(1), async {
+                while events.data().await.is_some() {}
+            }).await;
+            assert!(rest.is_ok(), "event stream hasn't ended on shutdown");
+        }
+
+        #[tokio::test]
+        async fn take_jobs_concurrently() {
+            let state = state("take_jobs_concurrently").await;
+            let ids: Vec<i32> = state.db_client
+                .run(|repo| (0..8).map(|id| repo.add_job(id, USER_ID.into())).collect())
+                .await
+                .unwrap();
+
+            let takers: Vec<_> = (0..8)
+                .map(|_| {
+                    let db_client = state.db_client.clone();
+                    tokio::spawn(async move { db_client.run(|repo| repo.take_job()).await })
+                })
+                .collect();
+
+            let mut taken = Vec::new();
+            for taker in takers {
+                taken.push(taker.await.unwrap().unwrap().unwrap().id);
+            }
+            taken.sort();
+            assert_eq!(taken, ids);
+        }
+
+        #[tokio::test]
+        async fn retry_job_after_backoff() {
+            let state = state("retry_job_after_backoff").await;
+            let id = state.db_client.run(|repo| repo.add_job(1, USER_ID.into())).await.unwrap();
+            state.db_client.run(|repo| repo.take_job()).await.unwrap().unwrap();
+
+            let not_before = chrono::Utc::now() + Duration::from_secs(60);
+            state.db_client.run(move |repo| repo.retry_job(id, "failed".into(), not_before)).await.unwrap();
+            assert!(state.db_client.run(|repo| repo.take_job()).await.unwrap().is_none());
+
+            let not_before = chrono::Utc::now() - Duration::from_secs(1);
+            state.db_client.run(move |repo| repo.retry_job(id, "failed".into(), not_before)).await.unwrap();
+            let job = state.db_client.run(|repo| repo.take_job()).await.unwrap().unwrap();
+            assert_eq!((job.id, job.attempts), (id, 2));
+        }
+
+        #[tokio::test]
+        async fn health_and_metrics() {
+            let state = state("health_and_metrics").await;
+            let app = router(state.clone());
+
+            let (status, _) = call(&app, Method::GET, "/healthz", None).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (status, body) = call(&app, Method::GET, "/readyz", None).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(readiness["checks"]["telegram"], "failing");
+            assert_eq!(readiness["checks"]["db"], "ok");
+
+            state.metrics.tg_token_valid.set(1);
+            let (status, _) = call(&app, Method::GET, "/readyz", None).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (_, id) = call(&app, Method::POST, "/sentences", Some(r#"{"text": "Привет"}"#)).await;
+            let id = String::from_utf8(id).unwrap();
+            play(&state, &app, &id).await;
+
+            let (status, body) = call(&app, Method::GET, "/metrics", None).await;
+            assert_eq!(status, StatusCode::OK);
+            let body = String::from_utf8(body).unwrap();
+            assert!(body.contains(r#"read4me_audio_cache_total{result="miss"} 1"#), "{body}");
+            assert!(body.contains(r#"read4me_audio_cache_total{result="hit"}"#), "{body}");
+            assert!(body.contains("read4me_tts_synthesis_seconds_count 1"), "{body}");
+
+            state.shutdown.cancel();
+            let (status, _) = call(&app, Method::GET, "/readyz", None).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        #[tokio::test]
+        async fn play_unknown_sentence() {
+            let app = app("play_unknown_sentence").await;
+
+            let (status, body) = call(&app, Method::POST, "/sentences/404/play", None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"], "sentence id='404' is not found");
+            assert!(!body["request_id"].as_str().unwrap().is_empty());
+
+            let req = Request::builder()
+                .method(Method::GET)
+                .uri("/jobs/404")
+                .header(header::COOKIE, format!("id={USER_ID}"))
+                .header("x-request-id", "req-1")
+                .body(Body::empty())
+                .unwrap();
+            let resp = app.oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            assert_eq!(resp.headers()["x-request-id"], "req-1");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["request_id"], "req-1");
+        }
+
+        async fn call_api(
+            app: &Router,
+            method: Method,
+            uri: &str,
+            token: &str,
+            body: Option<&str>,
+        ) -> (StatusCode, serde_json::Value) {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.unwrap_or_default().to_string()))
+                .unwrap();
+
+            let resp = app.clone().oneshot(req).await.unwrap();
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+            (status, serde_json::from_slice(&body).unwrap_or_default())
+        }
+
+        #[tokio::test]
+        async fn api() {
+            let state = state("api").await;
+            let app = router(state.clone());
+
+            let token = token::generate();
+            let token_hash = token::hash(&token);
+            state.db_client
+                .run(|repo| repo.add_api_token("script".into(), token_hash, USER_ID.into()))
+                .await
+                .unwrap();
+            let res = state.db_client
+                .run(|repo| repo.add_api_token("script".into(), token::hash("other"), USER_ID.into()))
+                .await;
+            assert!(matches!(res, Err(db::Error::Conflict(_))));
+
+            let (status, _) = call_api(&app, Method::GET, "/api/v1/sentences", "wrong", None).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+            let (status, s) = call_api(
+                &app, Method::POST, "/api/v1/sentences", &token, Some(r#"{"text": "Hello there", "tags": ["Greeting"]}"#),
+            ).await;
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(s["lang"], "en");
+            assert_eq!(s["tags"], serde_json::json!(["greeting"]));
+            let id = s["id"].as_i64().unwrap();
+
+            let (status, s) = call_api(
+                &app, Method::PATCH, &format!("/api/v1/sentences/{id}"), &token, Some(r#"{"text": "Hello"}"#),
+            ).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(s["text"], "Hello");
+
+            // a rejected update changes nothing
+            let (status, _) = call_api(
+                &app, Method::PATCH, &format!("/api/v1/sentences/{id}"), &token,
+                Some(r#"{"text": "Bye", "lang": "xx", "tags": ["farewell"]}"#),
+            ).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (_, s) = call_api(&app, Method::GET, &format!("/api/v1/sentences/{id}"), &token, None).await;
+            assert_eq!((&s["text"], &s["tags"]), (&serde_json::json!("Hello"), &serde_json::json!(["greeting"])));
+
+            let (status, page) = call_api(&app, Method::GET, "/api/v1/sentences?tag=greeting", &token, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(page["sentences"][0]["id"], id);
+
+            let play_url = format!("/api/v1/sentences/{id}/play");
+            let (status, job) = call_api(&app, Method::POST, &play_url, &token, None).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            assert_eq!(job["status"], "queued");
+
+            while run_next_job(&state).await.unwrap() {}
+
+            let (status, job) = call_api(&app, Method::GET, &format!("/api/v1/jobs/{}", job["id"]), &token, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(job["status"], "done");
+
+            let (status, playback) = call_api(&app, Method::POST, &play_url, &token, None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(playback["url"], job["url"]);
+
+            let (status, _) = call_api(&app, Method::DELETE, &format!("/api/v1/sentences/{id}"), &token, None).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+
+            let (status, err) = call_api(&app, Method::GET, &format!("/api/v1/sentences/{id}"), &token, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(err["request_id"].is_string());
+
+            let (status, doc) = call_api(&app, Method::GET, "/api/v1/openapi.json", "", None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(doc["paths"]["/sentences/{id}/play"]["post"].is_object());
+            assert!(doc["components"]["securitySchemes"]["token"].is_object());
+        }
+
+        #[tokio::test]
+        async fn secure_cookie_behind_proxy() {
+            let state = AppState {
+                trusted_proxies: Some(servekit::TrustedProxies::parse("127.0.0.1").unwrap()),
+                ..state("secure_cookie_behind_proxy").await
+            };
+            let app = router(state);
+
+            // headers of a peer which isn't a trusted proxy are ignored
+            for (peer, proto, is_secure) in [
+                ([127, 0, 0, 1], "https", true),
+                ([127, 0, 0, 1], "http", false),
+                ([198, 51, 100, 1], "https", false),
+            ] {
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri("/auth")
+                    .extension(ConnectInfo(SocketAddr::from((peer, 40000))))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header("x-forwarded-proto", proto)
+                    .header("x-forwarded-for", "203.0.113.7, 10.0.0.1")
+                    .body(Body::from(format!(r#"{{"tg_id": "{USER_ID}"}}"#)))
+                    .unwrap();
+                let resp = app.clone().oneshot(req).await.unwrap();
+
+                assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+                let cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+                assert_eq!(cookie.contains("Secure"), is_secure, "{cookie}");
+            }
+        }
+
+        #[tokio::test]
+        async fn reject_unknown_user() {
+            let app = app("reject_unknown_user").await;
+
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/sentences/1/play")
+                .header(header::COOKIE, "id=1")
+                .body(Body::empty())
+                .unwrap();
+            let resp = app.oneshot(req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
+
+pub mod fs {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use sha2::{Digest, Sha256};
+    use tower_http::services::ServeDir;
+
+    use crate::audio;
+    use crate::rpc::tts;
+
+    /// Directory with synthesised audio and prompts, served under `urls::ASSETS`.
+    #[derive(Clone)]
+    pub struct Assets {
+        dir: Arc<String>,
+    }
+
+    impl Assets {
+        pub async fn new(dir: &str) -> Self {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .expect("unable to create dir for storing assets");
+
+            Self { dir: Arc::new(dir.to_string()) }
+        }
+
+        pub fn serve_dir(&self) -> ServeDir {
+            ServeDir::new(self.dir.as_str())
+        }
+
+        pub async fn add_audio(
+            &self,
+            audio_key: &str,
+            format: audio::Format,
+            audio: Vec<u8>,
+        ) -> Result<String, String> {
+            self.write_audio(audio_uri(audio_key, format), audio).await
+        }
+
+        pub async fn add_template_audio(
+            &self,
+            id: i32,
+            audio_key: &str,
+            format: audio::Format,
+            audio: Vec<u8>,
+        ) -> Result<String, String> {
+            self.write_audio(template_audio_uri(id, audio_key, format), audio).await
+        }
+
+        pub async fn add_prompt(&self, id: i32, audio: Vec<u8>) -> Result<String, String> {
+            self.write_audio(format!("prompt_{id}.{}", audio::Format::Wav.extension()), audio).await
+        }
+
+        pub async fn is_audio_exist(&self, uri: &str) -> bool {
+            tokio::fs::try_exists(self.audio_path(uri)).await.unwrap_or(false)
+        }
+
+        pub async fn read_audio(&self, uri: &str) -> Result<Vec<u8>, String> {
+            tokio::fs::read(self.audio_path(uri))
+                .await
+                .map_err(|err| format!("unable to read audio with uri='{uri}': {err}"))
+        }
+
+        pub async fn drop_audio(&self, uri: &str) -> Result<(), String> {
+            match tokio::fs::remove_file(self.audio_path(uri)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("unable to drop audio with uri='{uri}': {err}"))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Removes files for which `is_referenced` is false given the name and age, files
+        /// modified within `grace` are kept as they may be not recorded in the db yet.
+        pub async fn remove_orphans(
+            &self,
+            grace: Duration,
+            is_referenced: impl Fn(&str, Duration) -> bool,
+        ) -> Result<Vec<String>, String> {
+            let mut dir = tokio::fs::read_dir(self.dir.as_str())
+                .await
+                .map_err(|err| format!("unable to read assets dir: {err}"))?;
+
+            let mut removed = Vec::new();
+
+            while let Some(entry) = dir.next_entry().await
+                .map_err(|err| format!("unable to read assets dir entry: {err}"))? {
+                let Ok(name) = entry.file_name().into_string() else { continue };
+                let Ok(meta) = entry.metadata().await else { continue };
+
+                let age = meta.modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .unwrap_or_default();
+
+                if !meta.is_file() || age < grace || is_referenced(&name, age) {
+                    continue;
+                }
+
+                self.drop_audio(&name).await?;
+                removed.push(name);
+            }
+
+            Ok(removed)
+        }
+
+        async fn write_audio(&self, name: String, audio: Vec<u8>) -> Result<String, String> {
+            tokio::fs::write(self.audio_path(&name), audio).await
+                .map_err(|err| format!("unable to save audio: {err}"))?;
+
+            Ok(name)
+        }
+
+        pub fn audio_path(&self, uri: &str) -> String {
+            format!("{}/{uri}", self.dir)
+        }
+    }
+
+    /// Content address of synthesised audio, sentences with the same text
+    /// and synthesis settings share the file.
+    pub fn audio_key(text: &str, voice: &tts::Voice, format: audio::Format) -> String {
+        hash(&format!("{text}|{}", settings(voice, format)))
+    }
+
+    pub fn audio_uri(audio_key: &str, format: audio::Format) -> String {
+        format!("{audio_key}.{}", format.extension())
+    }
+
+    pub fn template_audio_key(
+        template: &str,
+        values: &[(String, String)],
+        prompt_uri: Option<&str>,
+        voice: &tts::Voice,
+        format: audio::Format,
+    ) -> String {
+        hash(&format!("{template}|{values:?}|{prompt_uri:?}|{}", settings(voice, format)))
+    }
+
+    pub fn template_audio_uri(id: i32, audio_key: &str, format: audio::Format) -> String {
+        format!("{}{audio_key}.{}", template_audio_prefix(id), format.extension())
+    }
+
+    /// Template audio is a cache of the template's filled variables,
+    /// it lives as long as the template and is evicted by age.
+    pub fn template_audio_prefix(id: i32) -> String {
+        format!("tmpl_{id}_")
+    }
+
+    fn settings(voice: &tts::Voice, format: audio::Format) -> String {
+        format!(
+            "{}|{:?}|{}|{:?}|{:?}|{}",
+            voice.voice, voice.role, voice.speed, voice.volume, voice.pitch_shift, format,
+        )
+    }
+
+    fn hash(s: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(s);
+
+        format!("{:x}", hasher.finalize())[..16].to_string()
+    }
+}
+
+/// Bearer tokens of the API, only their hashes are stored in the db.
+pub mod token {
+    use rand::RngCore;
+    use sha2::{Digest, Sha256};
+
+    const TOKEN_BYTES: usize = 32;
+
+    pub fn generate() -> String {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        rand::thread_rng().fill_bytes(&mut bytes);
+
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn hash(token: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(token);
+
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+/// Exchange formats of the sentences library.
+mod library {
+    use serde::{Deserialize, Serialize};
+
+    const CSV_TAGS_SEPARATOR: char = ';';
+
+    #[derive(Deserialize, Clone, Copy, Debug)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Format {
+        Csv,
+        Json,
+        /// One phrase per line followed by optional `#tags`.
+        Text,
+    }
+
+    impl Format {
+        pub fn content_type(&self) -> &'static str {
+            match self {
+                Format::Csv => "text/csv; charset=utf-8",
+                Format::Json => "application/json",
+                Format::Text => "text/plain; charset=utf-8",
+            }
+        }
+
+        pub fn extension(&self) -> &'static str {
+            match self {
+                Format::Csv => "csv",
+                Format::Json => "json",
+                Format::Text => "txt",
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    pub struct Item {
+        pub text: String,
+        #[serde(default)]
+        pub lang: Option<String>,
+        #[serde(default)]
+        pub tags: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct CsvRow {
+        text: String,
+        #[serde(default)]
+        lang: Option<String>,
+        #[serde(default)]
+        tags: String,
+    }
+
+    /// Parses the file skipping items with empty text.
+    pub fn parse(format: Format, data: &[u8]) -> Result<Vec<Item>, String> {
+        let items = match format {
+            Format::Csv => csv::Reader::from_reader(data)
+                .deserialize::<CsvRow>()
+                .map(|row| {
+                    let row = row.map_err(|err| format!("unable to parse csv: {err}"))?;
+                    Ok(Item {
+                        text: row.text,
+                        lang: row.lang.filter(|lang| !lang.is_empty()),
+                        tags: row.tags
+                            .split(CSV_TAGS_SEPARATOR)
+                            .filter(|tag| !tag.is_empty())
+                            .map(String::from)
+                            .collect(),
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?,
+            Format::Json => serde_json::from_slice(data)
+                .map_err(|err| format!("unable to parse json: {err}"))?,
+            Format::Text => std::str::from_utf8(data)
+                .map_err(|err| format!("unable to parse text: {err}"))?
+                .lines()
+                .map(parse_line)
+                .collect(),
+        };
+
+        Ok(items
+            .into_iter()
+            .map(|item| Item { text: item.text.trim().to_string(), ..item })
+            .filter(|item| !item.text.is_empty())
+            .collect())
+    }
+
+    pub fn render(format: Format, items: &[Item]) -> Result<Vec<u8>, String> {
+        match format {
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                for item in items {
+                    writer
+                        .serialize(CsvRow {
+                            text: item.text.clone(),
+                            lang: item.lang.clone(),
+                            tags: item.tags.join(&CSV_TAGS_SEPARATOR.to_string()),
+                        })
+                        .map_err(|err| format!("unable to write csv: {err}"))?;
+                }
+                writer.into_inner().map_err(|err| format!("unable to write csv: {err}"))
+            }
+            Format::Json => serde_json::to_vec_pretty(items)
+                .map_err(|err| format!("unable to write json: {err}")),
+            Format::Text => Ok(items
+                .iter()
+                .map(|item| {
+                    let text = item.text.lines().collect::<Vec<_>>().join(" ");
+                    let tags = item.tags.iter().map(|tag| format!(" #{tag}"));
+                    std::iter::once(text).chain(tags).collect::<String>() + "\n"
+                })
+                .collect::<String>()
+                .into_bytes()),
+        }
+    }
+
+    /// Trailing words starting with `#` are tags, the rest of the line is the text.
+    fn parse_line(line: &str) -> Item {
+        let mut text = line.trim_end();
+        let mut tags = Vec::new();
+
+        while let Some((rest, word)) = text.rsplit_once(char::is_whitespace) {
+            match word.strip_prefix('#') {
+                Some(tag) if !tag.is_empty() => {
+                    tags.push(tag.to_string());
+                    text = rest.trim_end();
+                }
+                _ => break,
+            }
+        }
+        tags.reverse();
+
+        Item { text: text.to_string(), lang: None, tags }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use crate::http::library::{Format, Item, parse, render};
+
+        #[test]
+        fn round_trip() {
+            let items = vec![
+                Item { text: "Привет, мир".into(), lang: Some("ru".into()), tags: vec!["greeting".into(), "daily".into()] },
+                Item { text: "Hello, \"world\"".into(), lang: None, tags: Vec::new() },
+            ];
+
+            for format in [Format::Csv, Format::Json] {
+                let data = render(format, &items).unwrap();
+                assert_eq!(parse(format, &data).unwrap(), items, "{format:?}");
+            }
+
+            let data = render(Format::Text, &items).unwrap();
+            assert_eq!(String::from_utf8(data.clone()).unwrap(), "Привет, мир #greeting #daily\nHello, \"world\"\n");
+
+            let parsed = parse(Format::Text, &data).unwrap();
+            assert_eq!(parsed[0].tags, items[0].tags);
+            assert_eq!(parsed[1].text, items[1].text);
+        }
+
+        #[test]
+        fn parse_text() {
+            let items = parse(Format::Text, "\n  Доброе утро #morning\n#1 in line\n".as_bytes()).unwrap();
+
+            assert_eq!(items, vec![
+                Item { text: "Доброе утро".into(), lang: None, tags: vec!["morning".into()] },
+                Item { text: "#1 in line".into(), lang: None, tags: Vec::new() },
+            ]);
+        }
+    }
+}
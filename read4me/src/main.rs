@@ -1,48 +1,260 @@
+use std::env;
+use std::sync::Arc;
+
 use serde::Deserialize;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
-#[cfg(feature = "dev")]
-mod tg;
+use crate::db::Repository;
 
+mod audio;
 mod db;
 mod rpc;
 mod http;
+mod metrics;
+mod tg;
 
 const APP_NAME: &str = "read4me";
 
 #[derive(Deserialize, Debug)]
 struct Config {
-    #[cfg(feature = "dev")]
     tg_token: String,
+    tg_webapp_url: String,
     tg_valid_user_ids: String,
     tg_root_user_ids: String,
-    ya_auth_token: String,
+    #[serde(default)]
+    tts_backend: TtsBackend,
+    ya_folder_id: Option<String>,
+    ya_auth_token: Option<String>,
+    ya_sa_key_path: Option<String>,
+    #[serde(default = "default_ya_tts_url")]
+    ya_tts_url: String,
+    #[serde(default = "default_ya_iam_url")]
+    ya_iam_url: String,
+    tts_audio_format: audio::Format,
+    db_path: String,
+    #[serde(default = "default_assets_dir")]
+    assets_dir: String,
+    #[serde(default = "default_synthesis_workers")]
+    synthesis_workers: usize,
     server_address: String,
-    cert_pem_path: String,
-    key_pem_path: String,
+    #[serde(default)]
+    server_mode: ServerMode,
+    cert_pem_path: Option<String>,
+    key_pem_path: Option<String>,
+    acme_domains: Option<String>,
+    acme_contact_email: Option<String>,
+    #[serde(default = "default_acme_cache_dir")]
+    acme_cache_dir: String,
+    acme_directory_url: Option<String>,
+    acme_ca_pem_path: Option<String>,
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum ServerMode {
+    #[default]
+    Tls,
+    /// Tls with certificates from an ACME server.
+    Acme,
+    /// Plain http behind a reverse proxy.
+    Http,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum TtsBackend {
+    #[default]
+    Yandex,
+    LocalTone,
+    LocalSilence,
+}
+
+fn default_assets_dir() -> String {
+    "./assets".into()
+}
+
+fn default_acme_cache_dir() -> String {
+    "./acme".into()
+}
+
+fn default_trusted_proxies() -> String {
+    "127.0.0.1,::1".into()
+}
+
+fn default_synthesis_workers() -> usize {
+    2
+}
+
+fn default_ya_tts_url() -> String {
+    "https://tts.api.cloud.yandex.net:443".into()
+}
+
+fn default_ya_iam_url() -> String {
+    "https://iam.api.cloud.yandex.net/iam/v1/tokens".into()
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    // commands only need the db, so the rest of the env isn't required for them
+    let mut args = env::args().skip(1);
+    if let Some(cmd) = args.next() {
+        let db_path = env::var("DB_PATH").expect("DB_PATH must be provided");
+        let db_client = || db::sqlite::Client::new(&db_path);
+        match cmd.as_str() {
+            "backup" => {
+                let dst_path = args.next().expect("backup path must be provided");
+                db::sqlite::backup(&db_path, &dst_path).expect("unable to backup db");
+                info!("db has been backed up to path={dst_path}");
+            }
+            "add-token" => {
+                let name = args.next().expect("token name must be provided");
+                let user_id = args.next().expect("user id must be provided");
+                let token = http::token::generate();
+                let token_hash = http::token::hash(&token);
+                match db_client().run(move |repo| repo.add_api_token(name, token_hash, user_id)).await {
+                    Ok(()) => println!("{token}"),
+                    Err(err) => {
+                        error!("unable to add api token: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "drop-token" => {
+                let name = args.next().expect("token name must be provided");
+                let dropped = db_client().run(move |repo| repo.drop_api_token(name))
+                    .await
+                    .expect("unable to drop api token");
+                if !dropped {
+                    error!("api token is not found");
+                }
+            }
+            "list-tokens" => {
+                let tokens = db_client().run(|repo| repo.list_api_tokens())
+                    .await
+                    .expect("unable to list api tokens");
+                for t in tokens {
+                    println!("{}\t{}\t{}", t.name, t.user_id, t.created_at);
+                }
+            }
+            _ => panic!("unknown command '{cmd}'"),
+        }
+        return;
+    }
+
     let cfg = envy::from_env::<Config>().expect("unable to parse env variables");
 
-    let db_client = db::sqlite::Client::new();
-    let tts_client = rpc::tts::Client::new(&cfg.ya_auth_token).await;
+    let db_client = db::sqlite::Client::new(&cfg.db_path);
+
+    let tts_client: Arc<dyn rpc::tts::SpeechSynthesizer> = match cfg.tts_backend {
+        TtsBackend::Yandex => {
+            let credentials = match (cfg.ya_sa_key_path, cfg.ya_auth_token) {
+                (Some(path), _) => rpc::iam::Credentials::ServiceAccount(
+                    rpc::iam::ServiceAccountKey::read(&path).expect("unable to read service account key"),
+                ),
+                (None, Some(token)) => rpc::iam::Credentials::OAuth(token),
+                (None, None) => panic!("YA_SA_KEY_PATH or YA_AUTH_TOKEN must be provided for yandex tts"),
+            };
+
+            Arc::new(rpc::tts::Client::new(rpc::tts::Config {
+                folder_id: cfg.ya_folder_id.expect("YA_FOLDER_ID must be provided for yandex tts"),
+                tts_url: cfg.ya_tts_url,
+                iam_url: cfg.ya_iam_url,
+                credentials,
+            }).await)
+        }
+        TtsBackend::LocalTone | TtsBackend::LocalSilence => {
+            assert_eq!(cfg.tts_audio_format, audio::Format::Wav, "local tts supports only wav format");
+            let sound = match cfg.tts_backend {
+                TtsBackend::LocalTone => rpc::local::Sound::Tone,
+                _ => rpc::local::Sound::Silence,
+            };
+            Arc::new(rpc::local::Synthesizer::new(sound))
+        }
+    };
 
-    let tg_valid_user_ids = cfg.tg_valid_user_ids.split(",").map(str::to_string).collect();
+    let tg_valid_user_ids: Vec<String> = cfg.tg_valid_user_ids.split(",").map(str::to_string).collect();
     let tg_root_user_ids = cfg.tg_root_user_ids.split(",").map(str::to_string).collect();
 
+    let metrics = metrics::Metrics::new();
+    match tg::init(&cfg.tg_token, &cfg.tg_webapp_url).await {
+        Ok(()) => metrics.tg_token_valid.set(1),
+        Err(tg::InitError::Config(err)) => {
+            error!("unable to set up telegram bot: {err}");
+            std::process::exit(1);
+        }
+        // the bot keeps polling and becomes ready once telegram answers
+        Err(tg::InitError::Unavailable(err)) => {
+            error!("unable to set up telegram bot, starting anyway: {err}");
+            metrics.tg_token_valid.set(0);
+        }
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(servekit::cancel_on_signal(shutdown.clone()));
+
+    let assets = http::fs::Assets::new(&cfg.assets_dir).await;
+    let events = http::server::events();
+
+    let bot = tokio::spawn(tg::bot::run(tg::bot::Config {
+        token: cfg.tg_token,
+        db_client: db_client.clone(),
+        tts_client: tts_client.clone(),
+        assets: assets.clone(),
+        events: events.clone(),
+        tg_valid_user_ids: tg_valid_user_ids.clone(),
+        shutdown: shutdown.clone(),
+        metrics: metrics.clone(),
+    }));
+
+    let listener = match cfg.server_mode {
+        ServerMode::Tls => servekit::Listener::Tls {
+            cert_pem_path: cfg.cert_pem_path.expect("CERT_PEM_PATH must be provided for tls mode"),
+            key_pem_path: cfg.key_pem_path.expect("KEY_PEM_PATH must be provided for tls mode"),
+        },
+        ServerMode::Acme => servekit::Listener::Acme(servekit::AcmeConfig {
+            domains: cfg.acme_domains
+                .expect("ACME_DOMAINS must be provided for acme mode")
+                .split(",")
+                .map(str::to_string)
+                .collect(),
+            contact_email: cfg.acme_contact_email,
+            cache_dir: cfg.acme_cache_dir,
+            directory_url: cfg.acme_directory_url,
+            ca_pem_path: cfg.acme_ca_pem_path,
+        }),
+        ServerMode::Http => servekit::Listener::Http(
+            servekit::TrustedProxies::parse(&cfg.trusted_proxies).expect("unable to parse TRUSTED_PROXIES"),
+        ),
+    };
+
     info!("starting web server on address={}...", cfg.server_address);
-    http::server::init(
-        db_client,
+    http::server::init(http::server::Config {
+        db_client: db_client.clone(),
         tts_client,
+        assets,
+        events,
+        audio_format: cfg.tts_audio_format,
         tg_valid_user_ids,
         tg_root_user_ids,
-        &cfg.server_address,
-        &cfg.cert_pem_path,
-        &cfg.key_pem_path,
-    ).await;
+        synthesis_workers: cfg.synthesis_workers,
+        address: cfg.server_address,
+        listener,
+        shutdown,
+        metrics,
+    }).await;
     info!("web server has been closed...");
+
+    if let Err(err) = bot.await {
+        error!("telegram bot has failed: {err}");
+    }
+
+    match db_client.checkpoint() {
+        Ok(()) => info!("db has been flushed"),
+        Err(err) => error!("unable to flush db: {err}"),
+    }
 }
@@ -0,0 +1,108 @@
+use std::future::Future;
+use std::time::Instant;
+
+use prometheus::{
+    Encoder,
+    Histogram,
+    HistogramOpts,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+
+/// Prometheus metrics shared by the web server and the telegram bot.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Telegram updates by `kind`: message, inline_query, other.
+    pub tg_updates: IntCounterVec,
+    /// Telegram messages by `status`: sent, failed.
+    pub tg_messages: IntCounterVec,
+    /// Set to 0 when telegram rejects the token.
+    pub tg_token_valid: IntGauge,
+    tts_synthesis_seconds: Histogram,
+    tts_errors: IntCounter,
+    /// Audio lookups by `result`: hit, miss.
+    pub audio_cache: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(crate::APP_NAME.into()), None)
+            .expect("unable to create metrics registry");
+
+        let tg_updates = IntCounterVec::new(
+            Opts::new("tg_updates_total", "Telegram updates handled"),
+            &["kind"],
+        ).unwrap();
+        let tg_messages = IntCounterVec::new(
+            Opts::new("tg_messages_total", "Telegram messages sent or failed to be sent"),
+            &["status"],
+        ).unwrap();
+        let tg_token_valid = IntGauge::new("tg_token_valid", "Whether telegram accepts the bot token").unwrap();
+        let tts_synthesis_seconds = Histogram::with_opts(
+            HistogramOpts::new("tts_synthesis_seconds", "Time of speech synthesis calls")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+        ).unwrap();
+        let tts_errors = IntCounter::new("tts_errors_total", "Failed speech synthesis calls").unwrap();
+        let audio_cache = IntCounterVec::new(
+            Opts::new("audio_cache_total", "Audio lookups served from the cache or synthesised"),
+            &["result"],
+        ).unwrap();
+
+        registry.register(Box::new(tg_updates.clone())).unwrap();
+        registry.register(Box::new(tg_messages.clone())).unwrap();
+        registry.register(Box::new(tg_token_valid.clone())).unwrap();
+        registry.register(Box::new(tts_synthesis_seconds.clone())).unwrap();
+        registry.register(Box::new(tts_errors.clone())).unwrap();
+        registry.register(Box::new(audio_cache.clone())).unwrap();
+
+        Self {
+            registry,
+            tg_updates,
+            tg_messages,
+            tg_token_valid,
+            tts_synthesis_seconds,
+            tts_errors,
+            audio_cache,
+        }
+    }
+
+    /// Awaits the synthesis recording its time and failure.
+    pub async fn time_synthesis<T>(&self, f: impl Future<Output=Result<T, String>>) -> Result<T, String> {
+        let started_at = Instant::now();
+        let res = f.await;
+
+        self.tts_synthesis_seconds.observe(started_at.elapsed().as_secs_f64());
+        if res.is_err() {
+            self.tts_errors.inc();
+        }
+
+        res
+    }
+
+    pub fn cache_hit(&self, hit: bool) {
+        self.audio_cache
+            .with_label_values(&[if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    pub fn message_sent<T, E>(&self, res: &Result<T, E>) {
+        self.tg_messages
+            .with_label_values(&[if res.is_ok() { "sent" } else { "failed" }])
+            .inc();
+    }
+
+    /// Renders the metrics in the prometheus text format.
+    pub fn render(&self) -> Result<String, String> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|err| format!("unable to encode metrics: {err}"))?;
+
+        String::from_utf8(buf).map_err(|err| format!("unable to encode metrics: {err}"))
+    }
+}
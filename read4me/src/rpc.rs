@@ -1,62 +1,157 @@
 pub mod tts {
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::Arc;
     use std::time::Duration;
 
+    use async_trait::async_trait;
     use internal::*;
-    use tonic::Request;
+    use serde::{Deserialize, Serialize};
+    use tonic::{Code, Request, Status};
     use tonic::transport::{Channel, ClientTlsConfig};
-    use tracing::error;
+    use tracing::warn;
+
+    use crate::audio;
+    use crate::rpc::iam;
 
     mod internal {
         tonic::include_proto!("speechkit.tts.v3");
     }
 
-    const FOLDER_ID: &str = "b1ghol6q54ma6v8o2mvk";
+    /// Max text length of a single utterance accepted by the service.
+    const MAX_UTTERANCE_LEN: usize = 250;
+    const PCM_SAMPLE_RATE: u32 = 22050;
+
+    /// Speaker settings sent to the synthesizer as hints.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct Voice {
+        pub voice: String,
+        pub role: Option<String>,
+        pub speed: f64,
+        pub volume: Option<f64>,
+        pub pitch_shift: Option<f64>,
+    }
+
+    impl Default for Voice {
+        fn default() -> Self {
+            Self {
+                voice: "ermil".into(),
+                role: Some("neutral".into()),
+                speed: 0.8,
+                volume: None,
+                pitch_shift: None,
+            }
+        }
+    }
+
+    impl Voice {
+        /// Default voice of the language, see `audio::lang::LANGS`.
+        pub fn for_lang(lang: &str) -> Self {
+            let voice = match lang {
+                "en" => "john",
+                "de" => "lea",
+                "kk" => "amira",
+                "uz" => "nigora",
+                _ => return Self::default(),
+            };
+
+            Self { voice: voice.into(), role: None, ..Self::default() }
+        }
+
+        fn hints(&self) -> Vec<Hints> {
+            let mut hints = vec![
+                Hints { hint: Some(hints::Hint::Speed(self.speed)) },
+                Hints { hint: Some(hints::Hint::Voice(self.voice.clone())) },
+            ];
+
+            if let Some(role) = &self.role {
+                hints.push(Hints { hint: Some(hints::Hint::Role(role.clone())) });
+            }
+            if let Some(volume) = self.volume {
+                hints.push(Hints { hint: Some(hints::Hint::Volume(volume)) });
+            }
+            if let Some(pitch_shift) = self.pitch_shift {
+                hints.push(Hints { hint: Some(hints::Hint::PitchShift(pitch_shift)) });
+            }
+
+            hints
+        }
+    }
+
+    /// Template variable, for audio prompts `value` is the text pronounced
+    /// in the recording between `start_ms` and `start_ms + length_ms`.
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    pub struct TemplateVar {
+        pub name: String,
+        pub value: String,
+        pub start_ms: Option<i64>,
+        pub length_ms: Option<i64>,
+    }
+
+    /// Recorded WAV audio of a template, used as the base of the synthesis
+    /// so only the variables are generated.
+    pub struct AudioPrompt {
+        pub audio: Vec<u8>,
+        pub variables: Vec<TemplateVar>,
+    }
+
+    /// Backend turning text into audio of the requested format.
+    #[async_trait]
+    pub trait SpeechSynthesizer: Send + Sync {
+        async fn synthesise_text(
+            &self,
+            text: String,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String>;
+
+        /// Synthesises `template` like `Hi {name}` with the variables filled in by `values`.
+        async fn synthesise_template(
+            &self,
+            template: String,
+            values: Vec<(String, String)>,
+            prompt: Option<AudioPrompt>,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String>;
+
+        /// Fails while synthesis can't succeed, e.g. without a fresh token.
+        fn check(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
 
-    const TTS_URL: &str = "https://tts.api.cloud.yandex.net:443";
-    const IAM_URL: &str = "https://iam.api.cloud.yandex.net/iam/v1/tokens";
+    pub struct Config {
+        pub folder_id: String,
+        pub tts_url: String,
+        pub iam_url: String,
+        pub credentials: iam::Credentials,
+    }
 
+    /// Yandex SpeechKit client.
+    #[derive(Clone)]
     pub struct Client {
         client: synthesizer_client::SynthesizerClient<Channel>,
-        token: Arc<Mutex<String>>,
+        token: iam::Token,
+        folder_id: Arc<String>,
     }
 
     impl Client {
-        pub async fn new(auth_token: &str) -> Self {
-            let auth_token = auth_token.to_string();
-            let token = Arc::new(Mutex::new(String::new()));
-            let token2 = token.clone();
-
-            tokio::spawn(async move {
-                let http_client = reqwest::Client::new();
-                let req_body = HashMap::from([("yandexPassportOauthToken", auth_token.as_str())]);
-                let mut interval = tokio::time::interval(Duration::from_secs(6 * 60 * 60));
-                loop {
-                    interval.tick().await;
-
-                    let res = http_client.post(IAM_URL)
-                        .json(&req_body)
-                        .send()
-                        .await;
-
-                    match res {
-                        Ok(body) => {
-                            let map = body
-                                .json::<HashMap<String, String>>()
-                                .await
-                                .expect("unable to parse json body");
-                            let val = map.get("iamToken")
-                                .expect("token field not found").clone();
-                            *token.lock().unwrap() = val;
-                        }
-                        Err(err) => error!("error requesting iam token: {}", err)
-                    };
-                }
+        pub async fn new(cfg: Config) -> Self {
+            let http_client = reqwest::Client::new();
+            let iam_url = Arc::new(cfg.iam_url);
+            let credentials = Arc::new(cfg.credentials);
+            let token = iam::Token::spawn(move || {
+                let http_client = http_client.clone();
+                let iam_url = iam_url.clone();
+                let credentials = credentials.clone();
+                async move { iam::request_token(&http_client, &iam_url, &credentials).await }
             });
 
-            let channel = Channel::from_static(TTS_URL)
-                .tls_config(ClientTlsConfig::new()).unwrap()
+            let mut endpoint = Channel::from_shared(cfg.tts_url.clone()).expect("invalid tts url");
+            if cfg.tts_url.starts_with("https") {
+                endpoint = endpoint.tls_config(ClientTlsConfig::new()).unwrap();
+            }
+
+            let channel = endpoint
                 .timeout(Duration::from_secs(5))
                 .rate_limit(5, Duration::from_secs(1))
                 .concurrency_limit(256)
@@ -64,41 +159,54 @@ pub mod tts {
                 .await
                 .expect("unable to connect a channel");
 
+            Self::with_channel(channel, token, cfg.folder_id)
+        }
+
+        fn with_channel(channel: Channel, token: iam::Token, folder_id: String) -> Self {
             Self {
                 client: synthesizer_client::SynthesizerClient::new(channel),
-                token: token2,
+                token,
+                folder_id: Arc::new(folder_id),
             }
         }
 
-        pub async fn synthesise_text(&mut self, text: String) -> Result<Vec<u8>, String> {
-            let mut req = Request::new(UtteranceSynthesisRequest {
-                model: "".into(),
-                hints: vec![
-                    Hints { hint: Some(hints::Hint::Speed(0.8)) },
-                    Hints { hint: Some(hints::Hint::Voice("ermil".into())) },
-                    Hints { hint: Some(hints::Hint::Role("neutral".into())) },
-                ],
-                output_audio_spec: None,
-                loudness_normalization_type: 0,
-                unsafe_mode: false,
-                utterance: Some(utterance_synthesis_request::Utterance::Text(text)),
-            });
+        /// Sends the request with a valid IAM token, a rejected token
+        /// is force-refreshed and the request is retried once.
+        async fn synthesise(&self, req: UtteranceSynthesisRequest) -> Result<Vec<u8>, String> {
+            let token = self.token.get().await?;
+
+            let res = match self.call(req.clone(), &token).await {
+                Err(status) if status.code() == Code::Unauthenticated => {
+                    warn!("iam token has been rejected, refreshing it: {}", status.message());
+                    let token = self.token.refresh(&token).await?;
+                    self.call(req, &token).await
+                }
+                res => res,
+            };
+
+            res.map_err(|err| format!("unable to synthesise the text: {err}"))
+        }
+
+        async fn call(&self, req: UtteranceSynthesisRequest, token: &str) -> Result<Vec<u8>, Box<Status>> {
+            let mut req = Request::new(req);
 
-            let token = format!("Bearer {}", self.token.lock().unwrap());
+            let token = format!("Bearer {token}");
 
             req.metadata_mut().insert("authorization", token.parse().unwrap());
-            req.metadata_mut().insert("x-folder-id", FOLDER_ID.parse().unwrap());
+            req.metadata_mut().insert(
+                "x-folder-id",
+                self.folder_id.parse().map_err(|_| Status::invalid_argument("invalid folder id"))?,
+            );
 
             let resp = self.client
+                .clone()
                 .utterance_synthesis(req)
-                .await
-                .map_err(|err| format!("unable to synthesise the text: {err}"))?;
+                .await?;
 
             let mut resp = resp.into_inner();
             let mut audio = Vec::new();
 
-            while let Some(it) = resp.message().await
-                .map_err(|err| format!("unable to read the response: {err}"))? {
+            while let Some(it) = resp.message().await? {
                 if let Some(mut chunk) = it.audio_chunk {
                     audio.append(&mut chunk.data);
                 }
@@ -107,4 +215,629 @@ pub mod tts {
             Ok(audio)
         }
     }
+
+    #[async_trait]
+    impl SpeechSynthesizer for Client {
+        /// Synthesises text of any length: texts longer than a single utterance
+        /// are split on sentences, the chunks are synthesised concurrently within
+        /// the channel rate limit and stitched into one file.
+        async fn synthesise_text(
+            &self,
+            text: String,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String> {
+            let chunks = audio::text::split(&text, MAX_UTTERANCE_LEN);
+
+            if chunks.len() <= 1 {
+                return self.synthesise(utterance_request(text, voice, audio_spec(format))).await;
+            }
+
+            let spec = match format {
+                audio::Format::Wav => raw_audio_spec(),
+                audio::Format::Mp3 => audio_spec(format),
+                // chained ogg streams are poorly supported by players, so let the service split the text
+                audio::Format::OggOpus => {
+                    let mut req = utterance_request(text, voice, audio_spec(format));
+                    req.unsafe_mode = true;
+                    return self.synthesise(req).await;
+                }
+            };
+
+            let tasks: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    let client = self.clone();
+                    let req = utterance_request(chunk, voice, spec.clone());
+                    tokio::spawn(async move { client.synthesise(req).await })
+                })
+                .collect();
+
+            let mut audio = Vec::new();
+
+            for task in tasks {
+                let mut chunk = task.await
+                    .map_err(|err| format!("unable to join synthesis task: {err}"))??;
+                audio.append(&mut chunk);
+            }
+
+            match format {
+                audio::Format::Wav => Ok(audio::wav::encode(&audio, PCM_SAMPLE_RATE)),
+                _ => Ok(audio),
+            }
+        }
+
+        async fn synthesise_template(
+            &self,
+            template: String,
+            values: Vec<(String, String)>,
+            prompt: Option<AudioPrompt>,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String> {
+            let mut hints = voice.hints();
+
+            if let Some(prompt) = prompt {
+                let audio_template = AudioTemplate {
+                    audio: Some(AudioContent {
+                        audio_source: Some(audio_content::AudioSource::Content(prompt.audio)),
+                        audio_spec: Some(audio_spec(audio::Format::Wav)),
+                    }),
+                    text_template: Some(TextTemplate {
+                        text_template: template.clone(),
+                        variables: prompt.variables
+                            .iter()
+                            .map(|v| TextVariable {
+                                variable_name: v.name.clone(),
+                                variable_value: v.value.clone(),
+                            })
+                            .collect(),
+                    }),
+                    variables: prompt.variables
+                        .iter()
+                        .map(|v| AudioVariable {
+                            variable_name: v.name.clone(),
+                            variable_start_ms: v.start_ms.unwrap_or_default(),
+                            variable_length_ms: v.length_ms.unwrap_or_default(),
+                        })
+                        .collect(),
+                };
+
+                hints.push(Hints { hint: Some(hints::Hint::AudioTemplate(audio_template)) });
+            }
+
+            let req = UtteranceSynthesisRequest {
+                model: "".into(),
+                hints,
+                output_audio_spec: Some(audio_spec(format)),
+                loudness_normalization_type: 0,
+                unsafe_mode: false,
+                utterance: Some(utterance_synthesis_request::Utterance::TextTemplate(TextTemplate {
+                    text_template: template,
+                    variables: values
+                        .into_iter()
+                        .map(|(name, value)| TextVariable { variable_name: name, variable_value: value })
+                        .collect(),
+                })),
+            };
+
+            self.synthesise(req).await
+        }
+
+        fn check(&self) -> Result<(), String> {
+            self.token.check()
+        }
+    }
+
+    fn utterance_request(text: String, voice: &Voice, spec: AudioFormatOptions) -> UtteranceSynthesisRequest {
+        UtteranceSynthesisRequest {
+            model: "".into(),
+            hints: voice.hints(),
+            output_audio_spec: Some(spec),
+            loudness_normalization_type: 0,
+            unsafe_mode: false,
+            utterance: Some(utterance_synthesis_request::Utterance::Text(text)),
+        }
+    }
+
+    fn raw_audio_spec() -> AudioFormatOptions {
+        AudioFormatOptions {
+            audio_format: Some(audio_format_options::AudioFormat::RawAudio(RawAudio {
+                audio_encoding: raw_audio::AudioEncoding::Linear16Pcm.into(),
+                sample_rate_hertz: PCM_SAMPLE_RATE.into(),
+            })),
+        }
+    }
+
+    fn audio_spec(format: audio::Format) -> AudioFormatOptions {
+        let typ = match format {
+            audio::Format::Wav => container_audio::ContainerAudioType::Wav,
+            audio::Format::Mp3 => container_audio::ContainerAudioType::Mp3,
+            audio::Format::OggOpus => container_audio::ContainerAudioType::OggOpus,
+        };
+
+        AudioFormatOptions {
+            audio_format: Some(audio_format_options::AudioFormat::ContainerAudio(ContainerAudio {
+                container_audio_type: typ.into(),
+            })),
+        }
+    }
+
+    /// Needs the generated server stubs, which aren't built for release.
+    #[cfg(all(test, tts_fake_server))]
+    mod test {
+        use std::pin::Pin;
+        use std::sync::{Arc, Mutex};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tokio_stream::Stream;
+        use tokio_stream::wrappers::TcpListenerStream;
+        use tonic::{Request, Response, Status};
+        use tonic::metadata::MetadataMap;
+        use tonic::transport::{Channel, Server};
+
+        use crate::audio;
+        use crate::rpc::iam;
+        use crate::rpc::tts::{Client, MAX_UTTERANCE_LEN, SpeechSynthesizer, Voice};
+        use crate::rpc::tts::internal::*;
+
+        const TOKEN: &str = "test-token";
+        const FOLDER_ID: &str = "test-folder";
+
+        /// Records received requests and streams back `chunks` for every one of them,
+        /// requests authorized with `rejected_token` fail as unauthenticated.
+        #[derive(Clone)]
+        struct FakeSynthesizer {
+            chunks: Vec<Vec<u8>>,
+            rejected_token: Option<String>,
+            requests: Arc<Mutex<Vec<(MetadataMap, UtteranceSynthesisRequest)>>>,
+        }
+
+        #[tonic::async_trait]
+        impl synthesizer_server::Synthesizer for FakeSynthesizer {
+            type UtteranceSynthesisStream = Pin<Box<dyn Stream<Item=Result<UtteranceSynthesisResponse, Status>> + Send>>;
+
+            async fn utterance_synthesis(
+                &self,
+                req: Request<UtteranceSynthesisRequest>,
+            ) -> Result<Response<Self::UtteranceSynthesisStream>, Status> {
+                let metadata = req.metadata().clone();
+                let authorization = metadata.get("authorization").cloned();
+                self.requests.lock().unwrap().push((metadata, req.into_inner()));
+
+                if let (Some(token), Some(authorization)) = (&self.rejected_token, authorization) {
+                    if authorization == format!("Bearer {token}").as_str() {
+                        return Err(Status::unauthenticated("token is expired"));
+                    }
+                }
+
+                let resp: Vec<_> = self.chunks
+                    .iter()
+                    .map(|data| UtteranceSynthesisResponse {
+                        audio_chunk: Some(AudioChunk { data: data.clone() }),
+                    })
+                    .collect();
+
+                Ok(Response::new(Box::pin(tokio_stream::iter(resp.into_iter().map(Ok)))))
+            }
+        }
+
+        async fn start(chunks: Vec<Vec<u8>>) -> (Client, FakeSynthesizer) {
+            let token = iam::Token::spawn(|| async { Ok((TOKEN.to_string(), iam::expires_in(3600))) });
+            start_with(chunks, None, token).await
+        }
+
+        async fn start_with(
+            chunks: Vec<Vec<u8>>,
+            rejected_token: Option<String>,
+            token: iam::Token,
+        ) -> (Client, FakeSynthesizer) {
+            let fake = FakeSynthesizer { chunks, rejected_token, requests: Arc::default() };
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(Server::builder()
+                .add_service(synthesizer_server::SynthesizerServer::new(fake.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)));
+
+            let channel = Channel::from_shared(format!("http://{addr}")).unwrap()
+                .connect()
+                .await
+                .unwrap();
+            let client = Client::with_channel(channel, token, FOLDER_ID.into());
+
+            (client, fake)
+        }
+
+        fn hint_voice(req: &UtteranceSynthesisRequest) -> Option<&str> {
+            req.hints.iter().find_map(|h| match &h.hint {
+                Some(hints::Hint::Voice(voice)) => Some(voice.as_str()),
+                _ => None,
+            })
+        }
+
+        #[tokio::test]
+        async fn synthesise_short_text() {
+            let (client, fake) = start(vec![b"ab".to_vec(), b"cd".to_vec()]).await;
+            let voice = Voice { pitch_shift: Some(100.0), ..Voice::default() };
+
+            let audio = client.synthesise_text("Привет".into(), &voice, audio::Format::Mp3).await.unwrap();
+
+            assert_eq!(audio, b"abcd");
+
+            let requests = fake.requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+
+            let (metadata, req) = &requests[0];
+            assert_eq!(metadata.get("authorization").unwrap(), &format!("Bearer {TOKEN}"));
+            assert_eq!(metadata.get("x-folder-id").unwrap(), FOLDER_ID);
+            assert_eq!(req.utterance, Some(utterance_synthesis_request::Utterance::Text("Привет".into())));
+            assert_eq!(hint_voice(req), Some("ermil"));
+            assert!(req.hints.iter().any(|h| h.hint == Some(hints::Hint::PitchShift(100.0))));
+            assert!(req.hints.iter().any(|h| h.hint == Some(hints::Hint::Role("neutral".into()))));
+        }
+
+        #[tokio::test]
+        async fn synthesise_long_wav_text() {
+            let (client, fake) = start(vec![vec![1, 0], vec![2, 0]]).await;
+            let text = "Предложение. ".repeat(MAX_UTTERANCE_LEN / 10);
+
+            let audio = client.synthesise_text(text, &Voice::default(), audio::Format::Wav).await.unwrap();
+
+            let requests = fake.requests.lock().unwrap();
+            assert_eq!(requests.len(), 2);
+            assert!(requests.iter().all(|(_, req)| matches!(
+                req.output_audio_spec.as_ref().and_then(|s| s.audio_format.as_ref()),
+                Some(audio_format_options::AudioFormat::RawAudio(_)),
+            )));
+
+            assert_eq!(&audio[0..4], b"RIFF");
+            assert_eq!(&audio[44..], &[1, 0, 2, 0, 1, 0, 2, 0]);
+        }
+
+        #[tokio::test]
+        async fn retry_with_refreshed_token() {
+            let issued = Arc::new(AtomicUsize::new(0));
+            let issued2 = issued.clone();
+            let token = iam::Token::spawn(move || {
+                let n = issued2.fetch_add(1, Ordering::SeqCst) + 1;
+                async move { Ok((format!("token-{n}"), iam::expires_in(3600))) }
+            });
+            let (client, fake) = start_with(vec![b"ok".to_vec()], Some("token-1".into()), token).await;
+
+            let audio = client.synthesise_text("Привет".into(), &Voice::default(), audio::Format::Mp3).await.unwrap();
+
+            assert_eq!(audio, b"ok");
+            assert_eq!(issued.load(Ordering::SeqCst), 2);
+
+            let requests = fake.requests.lock().unwrap();
+            let tokens: Vec<_> = requests
+                .iter()
+                .map(|(metadata, _)| metadata.get("authorization").unwrap().to_str().unwrap())
+                .collect();
+            assert_eq!(tokens, vec!["Bearer token-1", "Bearer token-2"]);
+        }
+    }
+}
+
+pub mod iam {
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::{Notify, watch};
+    use tracing::{error, info};
+
+    /// Upper bound of a token refresh period, tokens are valid for up to 12 hours.
+    const MAX_REFRESH_PERIOD: Duration = Duration::from_secs(60 * 60);
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+    /// How long a call waits for a valid token before failing.
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+    const JWT_TTL_SECS: i64 = 60 * 60;
+
+    /// Credentials exchanged for IAM tokens.
+    pub enum Credentials {
+        /// Yandex passport OAuth token.
+        OAuth(String),
+        /// Service account authorized key, signs JWTs.
+        ServiceAccount(ServiceAccountKey),
+    }
+
+    /// Authorized key file created by `yc iam key create`.
+    #[derive(Deserialize)]
+    pub struct ServiceAccountKey {
+        id: String,
+        service_account_id: String,
+        private_key: String,
+    }
+
+    impl ServiceAccountKey {
+        pub fn read(path: &str) -> Result<Self, String> {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| format!("unable to read service account key with path='{path}': {err}"))?;
+
+            serde_json::from_str(&content)
+                .map_err(|err| format!("unable to parse service account key: {err}"))
+        }
+
+        /// Builds a PS256 JWT to exchange for an IAM token at `aud`.
+        fn jwt(&self, aud: &str) -> Result<String, String> {
+            #[derive(Serialize)]
+            struct Claims<'a> {
+                iss: &'a str,
+                aud: &'a str,
+                iat: i64,
+                exp: i64,
+            }
+
+            // the key is prefixed with a comment line which isn't a part of the pem
+            let pem = self.private_key
+                .find("-----BEGIN")
+                .map(|start| &self.private_key[start..])
+                .unwrap_or(&self.private_key);
+            let key = EncodingKey::from_rsa_pem(pem.as_bytes())
+                .map_err(|err| format!("invalid service account private key: {err}"))?;
+
+            let mut header = Header::new(Algorithm::PS256);
+            header.kid = Some(self.id.clone());
+
+            let now = Utc::now().timestamp();
+            let claims = Claims {
+                iss: &self.service_account_id,
+                aud,
+                iat: now,
+                exp: now + JWT_TTL_SECS,
+            };
+
+            jsonwebtoken::encode(&header, &claims, &key)
+                .map_err(|err| format!("unable to sign jwt: {err}"))
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TokenResponse {
+        iam_token: String,
+        expires_at: DateTime<Utc>,
+    }
+
+    type Value = Option<(String, DateTime<Utc>)>;
+
+    /// IAM token kept fresh by a background task. The token is refreshed
+    /// in the middle of its lifetime, failed requests are retried with backoff.
+    #[derive(Clone)]
+    pub struct Token {
+        rx: watch::Receiver<Value>,
+        refresh: Arc<Notify>,
+    }
+
+    impl Token {
+        pub fn spawn<F, Fut>(request: F) -> Self
+            where
+                F: Fn() -> Fut + Send + 'static,
+                Fut: Future<Output=Result<(String, DateTime<Utc>), String>> + Send,
+        {
+            let (tx, rx) = watch::channel(None);
+            let refresh = Arc::new(Notify::new());
+            let refresh2 = refresh.clone();
+
+            tokio::spawn(async move {
+                let mut backoff = MIN_BACKOFF;
+                loop {
+                    let (token, expires_at) = match request().await {
+                        Ok(it) => it,
+                        Err(err) => {
+                            error!("unable to refresh iam token, retrying in {backoff:?}: {err}");
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                            continue;
+                        }
+                    };
+
+                    info!("iam token has been refreshed, expires_at={expires_at}");
+                    tx.send_replace(Some((token, expires_at)));
+                    backoff = MIN_BACKOFF;
+
+                    let period = (expires_at - Utc::now())
+                        .to_std()
+                        .map(|ttl| (ttl / 2).min(MAX_REFRESH_PERIOD))
+                        .unwrap_or(MIN_BACKOFF);
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(period) => {}
+                        _ = refresh2.notified() => {}
+                    }
+                }
+            });
+
+            Self { rx, refresh }
+        }
+
+        /// Returns the current token, waiting for a valid one if it's missing or expired.
+        pub async fn get(&self) -> Result<String, String> {
+            self.wait(|_| true).await
+        }
+
+        /// Forces a refresh of the `rejected` token and waits for a new one.
+        pub async fn refresh(&self, rejected: &str) -> Result<String, String> {
+            self.refresh.notify_one();
+            self.wait(|token| token != rejected).await
+        }
+
+        /// Fails unless there is a token which hasn't expired.
+        pub fn check(&self) -> Result<(), String> {
+            match &*self.rx.borrow() {
+                None => Err("iam token hasn't been issued yet".into()),
+                Some((_, expires_at)) if *expires_at <= Utc::now() => Err(format!("iam token has expired at {expires_at}")),
+                Some(_) => Ok(()),
+            }
+        }
+
+        async fn wait(&self, accept: impl Fn(&str) -> bool) -> Result<String, String> {
+            let mut rx = self.rx.clone();
+            let is_valid = |val: &Value| matches!(val, Some((token, expires_at)) if *expires_at > Utc::now() && accept(token));
+
+            let val = tokio::time::timeout(WAIT_TIMEOUT, rx.wait_for(is_valid))
+                .await
+                .map_err(|_| "timed out waiting for a valid iam token".to_string())?
+                .map_err(|err| format!("unable to get iam token: {err}"))?;
+
+            Ok(val.as_ref().map(|(token, _)| token.clone()).unwrap_or_default())
+        }
+    }
+
+    pub async fn request_token(
+        http_client: &reqwest::Client,
+        url: &str,
+        credentials: &Credentials,
+    ) -> Result<(String, DateTime<Utc>), String> {
+        let req_body = match credentials {
+            Credentials::OAuth(token) => HashMap::from([("yandexPassportOauthToken", token.clone())]),
+            Credentials::ServiceAccount(key) => HashMap::from([("jwt", key.jwt(url)?)]),
+        };
+
+        let resp = http_client.post(url)
+            .json(&req_body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("unable to request iam token: {err}"))?;
+
+        let body = resp.json::<TokenResponse>()
+            .await
+            .map_err(|err| format!("unable to parse iam token response: {err}"))?;
+
+        Ok((body.iam_token, body.expires_at))
+    }
+
+    #[cfg(test)]
+    pub fn expires_in(secs: i64) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(secs)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+
+        use crate::rpc::iam;
+        use crate::rpc::iam::ServiceAccountKey;
+
+        #[test]
+        fn sign_service_account_jwt() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rpc/testdata/sa_key.json");
+            let key = ServiceAccountKey::read(path).unwrap();
+            let aud = "https://iam.api.cloud.yandex.net/iam/v1/tokens";
+
+            let jwt = key.jwt(aud).unwrap();
+
+            let header = jsonwebtoken::decode_header(&jwt).unwrap();
+            assert_eq!(header.alg, Algorithm::PS256);
+            assert_eq!(header.kid.as_deref(), Some("ajetestkeyid"));
+
+            let public_key: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            let public_key = DecodingKey::from_rsa_pem(public_key["public_key"].as_str().unwrap().as_bytes()).unwrap();
+            let mut validation = Validation::new(Algorithm::PS256);
+            validation.set_audience(&[aud]);
+            validation.set_issuer(&["ajetestaccount"]);
+
+            assert!(jsonwebtoken::decode::<serde_json::Value>(&jwt, &public_key, &validation).is_ok());
+        }
+
+        #[tokio::test]
+        async fn check_token_expiry() {
+            let token = iam::Token::spawn(|| async { Ok(("expired".to_string(), iam::expires_in(-1))) });
+            assert!(token.check().is_err());
+
+            let token = iam::Token::spawn(|| async { Ok(("fresh".to_string(), iam::expires_in(3600))) });
+            token.get().await.unwrap();
+            assert!(token.check().is_ok());
+        }
+    }
+}
+
+pub mod local {
+    use async_trait::async_trait;
+
+    use crate::audio;
+    use crate::rpc::tts::{AudioPrompt, SpeechSynthesizer, Voice};
+
+    const SAMPLE_RATE: u32 = 22050;
+    const CHAR_DURATION_MS: f64 = 60.0;
+    const BASE_FREQUENCY: f64 = 440.0;
+    const AMPLITUDE: f64 = 0.3 * i16::MAX as f64;
+
+    /// Kind of audio generated instead of speech.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Sound {
+        Tone,
+        Silence,
+    }
+
+    /// Offline synthesizer producing deterministic WAV audio: the duration depends
+    /// on the text length and the speed, the tone pitch on the voice pitch shift.
+    pub struct Synthesizer {
+        sound: Sound,
+    }
+
+    impl Synthesizer {
+        pub fn new(sound: Sound) -> Self {
+            Self { sound }
+        }
+
+        fn generate(&self, text: &str, voice: &Voice) -> Vec<u8> {
+            let duration_ms = text.chars().count() as f64 * CHAR_DURATION_MS / voice.speed.max(0.1);
+            let samples = (duration_ms * SAMPLE_RATE as f64 / 1000.0) as usize;
+            let frequency = BASE_FREQUENCY + voice.pitch_shift.unwrap_or_default();
+
+            let mut pcm = Vec::with_capacity(samples * 2);
+            for i in 0..samples {
+                let sample = match self.sound {
+                    Sound::Tone => {
+                        let t = i as f64 / SAMPLE_RATE as f64;
+                        (AMPLITUDE * (2.0 * std::f64::consts::PI * frequency * t).sin()) as i16
+                    }
+                    Sound::Silence => 0,
+                };
+                pcm.extend_from_slice(&sample.to_le_bytes());
+            }
+
+            audio::wav::encode(&pcm, SAMPLE_RATE)
+        }
+    }
+
+    #[async_trait]
+    impl SpeechSynthesizer for Synthesizer {
+        async fn synthesise_text(
+            &self,
+            text: String,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String> {
+            if format != audio::Format::Wav {
+                return Err(format!("local synthesizer supports only wav, got format='{format}'"));
+            }
+
+            Ok(self.generate(&text, voice))
+        }
+
+        async fn synthesise_template(
+            &self,
+            template: String,
+            values: Vec<(String, String)>,
+            _prompt: Option<AudioPrompt>,
+            voice: &Voice,
+            format: audio::Format,
+        ) -> Result<Vec<u8>, String> {
+            let text = values
+                .iter()
+                .fold(template, |text, (name, value)| text.replace(&format!("{{{name}}}"), value));
+
+            self.synthesise_text(text, voice, format).await
+        }
+    }
 }
@@ -1,19 +1,450 @@
-use frankenstein::{AsyncApi, AsyncTelegramApi, MenuButton, MenuButtonWebApp, SetChatMenuButtonParams, WebAppInfo};
+use frankenstein::{
+    AsyncApi,
+    AsyncTelegramApi,
+    BotCommand,
+    Error,
+    GetChatMenuButtonParams,
+    GetMyCommandsParams,
+    GetMyDescriptionParams,
+    GetMyShortDescriptionParams,
+    MenuButton,
+    MenuButtonWebApp,
+    SetChatMenuButtonParams,
+    SetMyCommandsParams,
+    SetMyDescriptionParams,
+    SetMyShortDescriptionParams,
+    WebAppInfo,
+};
+use tracing::info;
+
+const COMMANDS: [(&str, &str); 2] = [
+    ("start", "how to use the bot"),
+    ("help", "how to use the bot"),
+];
+const DESCRIPTION: &str = "Reads your phrases aloud. Send a phrase to get a voice message \
+    or open the app to manage the phrase library.";
+const SHORT_DESCRIPTION: &str = "Reads your phrases aloud";
+
+/// Error of `init`. `Config` errors need TG_TOKEN or TG_WEBAPP_URL to be fixed,
+/// on `Unavailable` ones the bot starts anyway.
+#[derive(Debug)]
+pub enum InitError {
+    Config(String),
+    Unavailable(String),
+}
+
+impl From<String> for InitError {
+    fn from(msg: String) -> Self {
+        InitError::Unavailable(msg)
+    }
+}
+
+/// Checks the token and brings the bot profile up to date,
+/// settings which already match are left untouched.
+pub async fn init(token: &str, webapp_url: &str) -> Result<(), InitError> {
+    check_webapp_url(webapp_url).map_err(InitError::Config)?;
 
-pub async fn init(token: &str, api_url: &str) {
     let api = AsyncApi::new(token);
-    set_chat_menu_btn(&api, api_url).await;
+    let me = api.get_me().await.map_err(|err| match err {
+        Error::Api(resp) if resp.error_code == 401 || resp.error_code == 404 => {
+            InitError::Config(format!("TG_TOKEN is rejected by telegram: {}", resp.description))
+        }
+        err => InitError::Unavailable(format!("unable to get bot info: {err}")),
+    })?;
+    info!("authorized as telegram bot @{}", me.result.username.unwrap_or_default());
+
+    set_chat_menu_btn(&api, webapp_url).await?;
+    set_commands(&api).await?;
+    Ok(set_description(&api).await?)
 }
 
-async fn set_chat_menu_btn(api: &AsyncApi, api_url: &str) {
+/// Telegram opens web apps only by https urls.
+fn check_webapp_url(webapp_url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(webapp_url)
+        .map_err(|err| format!("TG_WEBAPP_URL='{webapp_url}' is invalid: {err}"))?;
+
+    if url.scheme() != "https" {
+        return Err(format!("TG_WEBAPP_URL='{webapp_url}' must be an https url"));
+    }
+
+    Ok(())
+}
+
+async fn set_chat_menu_btn(api: &AsyncApi, webapp_url: &str) -> Result<(), InitError> {
+    let menu_button = MenuButton::WebApp(MenuButtonWebApp::builder()
+        .text(crate::APP_NAME)
+        .web_app(WebAppInfo::builder().url(webapp_url.to_string()).build())
+        .build());
+
+    let current = api.get_chat_menu_button(GetChatMenuButtonParams::builder().build()).await
+        .map_err(|err| format!("unable to get chat menu button: {err}"))?;
+    if current.result == menu_button {
+        return Ok(());
+    }
+
     api.set_chat_menu_button(
         SetChatMenuButtonParams::builder()
-            .menu_button(
-                MenuButton::WebApp(MenuButtonWebApp::builder()
-                    .text(crate::APP_NAME)
-                    .web_app(WebAppInfo::builder().url(api_url.to_string()).build())
-                    .build())
-            )
+            .menu_button(menu_button)
             .build(),
-    ).await.expect("unable to update chat menu button");
-}
\ No newline at end of file
+    ).await.map_err(|err| match err {
+        Error::Api(resp) if resp.error_code == 400 => {
+            InitError::Config(format!("TG_WEBAPP_URL='{webapp_url}' is rejected by telegram: {}", resp.description))
+        }
+        err => InitError::Unavailable(format!("unable to update chat menu button: {err}")),
+    })?;
+    info!("chat menu button has been updated");
+
+    Ok(())
+}
+
+async fn set_commands(api: &AsyncApi) -> Result<(), String> {
+    let commands: Vec<BotCommand> = COMMANDS
+        .iter()
+        .map(|(command, description)| BotCommand::builder()
+            .command(*command)
+            .description(*description)
+            .build())
+        .collect();
+
+    let current = api.get_my_commands(&GetMyCommandsParams::builder().build()).await
+        .map_err(|err| format!("unable to get bot commands: {err}"))?;
+    if current.result == commands {
+        return Ok(());
+    }
+
+    api.set_my_commands(&SetMyCommandsParams::builder().commands(commands).build()).await
+        .map_err(|err| format!("unable to update bot commands: {err}"))?;
+    info!("bot commands have been updated");
+
+    Ok(())
+}
+
+async fn set_description(api: &AsyncApi) -> Result<(), String> {
+    let current = api.get_my_description(&GetMyDescriptionParams::builder().build()).await
+        .map_err(|err| format!("unable to get bot description: {err}"))?;
+    if current.result.description != DESCRIPTION {
+        api.set_my_description(&SetMyDescriptionParams::builder().description(DESCRIPTION).build()).await
+            .map_err(|err| format!("unable to update bot description: {err}"))?;
+        info!("bot description has been updated");
+    }
+
+    let current = api.get_my_short_description(&GetMyShortDescriptionParams::builder().build()).await
+        .map_err(|err| format!("unable to get bot short description: {err}"))?;
+    if current.result.short_description != SHORT_DESCRIPTION {
+        api.set_my_short_description(
+            &SetMyShortDescriptionParams::builder().short_description(SHORT_DESCRIPTION).build(),
+        ).await.map_err(|err| format!("unable to update bot short description: {err}"))?;
+        info!("bot short description has been updated");
+    }
+
+    Ok(())
+}
+
+/// Bot replying to text messages with voice messages and offering
+/// already sent phrases in inline queries.
+pub mod bot {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use frankenstein::{
+        AllowedUpdate,
+        AnswerInlineQueryParams,
+        AsyncApi,
+        AsyncTelegramApi,
+        Error,
+        FileUpload,
+        GetUpdatesParams,
+        InlineQuery,
+        InlineQueryResult,
+        InlineQueryResultCachedVoice,
+        InputFile,
+        Message,
+        SendMessageParams,
+        SendVoiceParams,
+        UpdateContent,
+    };
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+    use tokio_util::task::TaskTracker;
+    use tracing::{error, info};
+
+    use crate::{audio, db};
+    use crate::db::Repository;
+    use crate::http::fs;
+    use crate::http::server::response::Event;
+    use crate::metrics::Metrics;
+    use crate::rpc::tts::SpeechSynthesizer;
+
+    /// Telegram accepts voice messages only in ogg opus.
+    const VOICE_FORMAT: audio::Format = audio::Format::OggOpus;
+    const POLL_TIMEOUT_SECS: u32 = 30;
+    const RETRY_PERIOD: Duration = Duration::from_secs(5);
+    const INLINE_RESULTS_LIMIT: u64 = 20;
+    const INLINE_CACHE_SECS: u32 = 10;
+    const HELP: &str = "Send me a phrase and I'll read it aloud. \
+        Mention me with a few words in any chat to share a phrase I've already read.";
+
+    pub struct Config {
+        pub token: String,
+        pub db_client: db::sqlite::Client,
+        pub tts_client: Arc<dyn SpeechSynthesizer>,
+        /// Voice messages are kept with the web app audio until they are uploaded.
+        pub assets: fs::Assets,
+        /// Sentences added by the bot show up on the open pages.
+        pub events: broadcast::Sender<Event>,
+        pub tg_valid_user_ids: Vec<String>,
+        /// Stops polling, the updates in progress are handled before `run` returns.
+        pub shutdown: CancellationToken,
+        pub metrics: Metrics,
+    }
+
+    struct Bot {
+        api: AsyncApi,
+        db_client: db::sqlite::Client,
+        tts_client: Arc<dyn SpeechSynthesizer>,
+        assets: fs::Assets,
+        events: broadcast::Sender<Event>,
+        tg_valid_user_ids: Vec<String>,
+        metrics: Metrics,
+    }
+
+    /// Long polls updates until shutdown, each update is handled in its own task.
+    pub async fn run(cfg: Config) {
+        let bot = Arc::new(Bot {
+            api: AsyncApi::new(&cfg.token),
+            db_client: cfg.db_client,
+            tts_client: cfg.tts_client,
+            assets: cfg.assets,
+            events: cfg.events,
+            tg_valid_user_ids: cfg.tg_valid_user_ids,
+            metrics: cfg.metrics,
+        });
+
+        info!("starting telegram bot...");
+        let tasks = TaskTracker::new();
+        let mut offset = None;
+        while !cfg.shutdown.is_cancelled() {
+            let mut params = GetUpdatesParams::builder()
+                .timeout(POLL_TIMEOUT_SECS)
+                .allowed_updates(vec![AllowedUpdate::Message, AllowedUpdate::InlineQuery])
+                .build();
+            params.offset = offset;
+
+            // updates of an interrupted poll aren't confirmed, telegram sends them again after restart
+            let res = tokio::select! {
+                _ = cfg.shutdown.cancelled() => break,
+                res = bot.api.get_updates(&params) => res,
+            };
+
+            let updates = match res {
+                Ok(resp) => {
+                    bot.metrics.tg_token_valid.set(1);
+                    resp.result
+                }
+                Err(err) => {
+                    if let Error::Api(resp) = &err {
+                        if resp.error_code == 401 || resp.error_code == 404 {
+                            bot.metrics.tg_token_valid.set(0);
+                        }
+                    }
+                    error!("unable to get telegram updates: {err}");
+                    tokio::time::sleep(RETRY_PERIOD).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                offset = Some(update.update_id as i64 + 1);
+
+                let bot = bot.clone();
+                tasks.spawn(async move {
+                    let kind = match &update.content {
+                        UpdateContent::Message(_) => "message",
+                        UpdateContent::InlineQuery(_) => "inline_query",
+                        _ => "other",
+                    };
+                    bot.metrics.tg_updates.with_label_values(&[kind]).inc();
+
+                    let res = match update.content {
+                        UpdateContent::Message(msg) => bot.on_message(msg).await,
+                        UpdateContent::InlineQuery(query) => bot.on_inline_query(query).await,
+                        _ => Ok(()),
+                    };
+
+                    if let Err(err) = res {
+                        error!("unable to handle telegram update id='{}': {err}", update.update_id);
+                    }
+                });
+            }
+        }
+
+        info!("stopping telegram bot, waiting for updates in progress...");
+        tasks.close();
+        tasks.wait().await;
+
+        // handled updates are confirmed by the next poll, otherwise they are sent again after restart
+        if let Some(offset) = offset {
+            let params = GetUpdatesParams::builder().offset(offset).limit(1u32).timeout(0u32).build();
+            if let Err(err) = bot.api.get_updates(&params).await {
+                error!("unable to confirm telegram updates: {err}");
+            }
+        }
+    }
+
+    impl Bot {
+        async fn on_message(&self, msg: Message) -> Result<(), String> {
+            let (Some(user), Some(text)) = (msg.from, msg.text) else {
+                return Ok(());
+            };
+            let chat_id = msg.chat.id;
+
+            if !self.tg_valid_user_ids.contains(&user.id.to_string()) {
+                return self.send_text(chat_id, "Sorry, you aren't allowed to use the bot.").await;
+            }
+            if text.starts_with('/') {
+                return self.send_text(chat_id, HELP).await;
+            }
+
+            self.send_voice(chat_id, user.id.to_string(), text).await
+        }
+
+        /// Offers the matching sentences which were already sent as voice messages in the user's voice.
+        async fn on_inline_query(&self, query: InlineQuery) -> Result<(), String> {
+            let user_id = query.from.id.to_string();
+
+            let results = if self.tg_valid_user_ids.contains(&user_id) {
+                let text = Some(query.query).filter(|text| !text.trim().is_empty());
+                self.db_client
+                    .run(move |repo| {
+                        let page = repo.list_sentences(db::sqlite::SentenceFilter {
+                            text,
+                            limit: INLINE_RESULTS_LIMIT,
+                            ..Default::default()
+                        })?;
+
+                        let mut results = Vec::new();
+                        for s in page.sentences {
+                            let lang = s.lang.clone().or_else(|| audio::lang::detect(&s.text));
+                            let voice = repo.resolve_voice(s.voice_id, lang.as_deref(), user_id.clone())?;
+                            let audio_key = fs::audio_key(&s.text, &voice, VOICE_FORMAT);
+
+                            if let Some(file_id) = repo.get_tg_voice(audio_key)? {
+                                results.push(InlineQueryResult::CachedVoice(
+                                    InlineQueryResultCachedVoice::builder()
+                                        .id(s.id.to_string())
+                                        .voice_file_id(file_id)
+                                        .title(s.text)
+                                        .build()
+                                ));
+                            }
+                        }
+                        Ok(results)
+                    }).await?
+            } else {
+                Vec::new()
+            };
+
+            let params = AnswerInlineQueryParams::builder()
+                .inline_query_id(query.id)
+                .results(results)
+                .cache_time(INLINE_CACHE_SECS)
+                .is_personal(true)
+                .build();
+
+            self.api.answer_inline_query(&params).await
+                .map_err(|err| format!("unable to answer inline query: {err}"))?;
+
+            Ok(())
+        }
+
+        /// Adds the text to the sentences unless it's there, the voice message is uploaded
+        /// once per audio and then sent by its telegram file id.
+        async fn send_voice(&self, chat_id: i64, user_id: String, text: String) -> Result<(), String> {
+            let (s, voice, added) = self.db_client
+                .run(move |repo| {
+                    let (s, added) = match repo.find_sentence(text.clone())? {
+                        Some(s) => (s, false),
+                        None => {
+                            let lang = audio::lang::detect(&text);
+                            (repo.get_sentence(repo.add_sentence(text, lang)?)?, true)
+                        }
+                    };
+                    let lang = s.lang.clone().or_else(|| audio::lang::detect(&s.text));
+                    let voice = repo.resolve_voice(s.voice_id, lang.as_deref(), user_id)?;
+                    Ok((s, voice, added))
+                }).await?;
+
+            if added {
+                let _ = self.events.send(Event::SentenceAdded { id: s.id });
+            }
+
+            let audio_key = fs::audio_key(&s.text, &voice, VOICE_FORMAT);
+            let key = audio_key.clone();
+            let file_id = self.db_client
+                .run(move |repo| repo.get_tg_voice(key)).await?;
+
+            self.metrics.cache_hit(file_id.is_some());
+            if let Some(file_id) = file_id {
+                return self.upload_voice(chat_id, FileUpload::String(file_id)).await.map(|_| ());
+            }
+
+            // the audio of a failed upload is reused by the next message, it's removed
+            // by the assets cleanup unless the upload succeeds
+            let uri = fs::audio_uri(&audio_key, VOICE_FORMAT);
+            if !self.assets.is_audio_exist(&uri).await {
+                let audio = self.metrics
+                    .time_synthesis(self.tts_client.synthesise_text(s.text, &voice, VOICE_FORMAT)).await
+                    .map_err(|err| format!("unable to synthesise text: {err}"))?;
+                self.assets.add_audio(&audio_key, VOICE_FORMAT, audio).await?;
+            }
+
+            let path = PathBuf::from(self.assets.audio_path(&uri));
+            if let Some(file_id) = self.upload_voice(chat_id, FileUpload::InputFile(InputFile { path })).await? {
+                self.db_client
+                    .run(move |repo| repo.update_tg_voice(audio_key, file_id, uri)).await?;
+            }
+
+            Ok(())
+        }
+
+        /// Returns file id of the sent voice message.
+        async fn upload_voice(&self, chat_id: i64, voice: FileUpload) -> Result<Option<String>, String> {
+            let params = SendVoiceParams::builder()
+                .chat_id(chat_id)
+                .voice(voice)
+                .build();
+
+            let res = self.api.send_voice(&params).await;
+            self.metrics.message_sent(&res);
+            let msg = res.map_err(|err| format!("unable to send voice: {err}"))?;
+
+            Ok(msg.result.voice.map(|voice| voice.file_id))
+        }
+
+        async fn send_text(&self, chat_id: i64, text: &str) -> Result<(), String> {
+            let params = SendMessageParams::builder()
+                .chat_id(chat_id)
+                .text(text)
+                .build();
+
+            let res = self.api.send_message(&params).await;
+            self.metrics.message_sent(&res);
+            res.map_err(|err| format!("unable to send message: {err}"))?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tg::check_webapp_url;
+
+    #[test]
+    fn validate_webapp_url() {
+        assert!(check_webapp_url("https://read4me.tw1.ru:8080").is_ok());
+        assert!(check_webapp_url("http://read4me.tw1.ru").is_err());
+        assert!(check_webapp_url("read4me.tw1.ru").is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Serving of the axum apps over tls, ACME issued tls or plain http,
+//! with graceful shutdown and health checks. Shared by advtm and read4me.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{Json, Router};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use ipnet::IpNet;
+use rustls_acme::axum::AxumAcceptor;
+use rustls_acme::caches::DirCache;
+use rustls_acme::futures_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+/// How long in-flight requests, and the apps' own background work, may take after shutdown.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const TLS_RELOAD_PERIOD: Duration = Duration::from_secs(60);
+
+pub enum Listener {
+    /// The certificate is reloaded when the files change, e.g. after certbot renewal.
+    Tls { cert_pem_path: String, key_pem_path: String },
+    /// The certificate is issued and renewed by an ACME server.
+    Acme(AcmeConfig),
+    /// Plain http behind a reverse proxy which terminates tls.
+    Http(TrustedProxies),
+}
+
+/// Certificates are validated with TLS-ALPN-01 challenges, so the server
+/// must be reachable on port 443 of the domains.
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub contact_email: Option<String>,
+    /// Keeps the account key and certificates between restarts.
+    pub cache_dir: String,
+    /// Let's Encrypt production directory is used if not set.
+    pub directory_url: Option<String>,
+    /// Root certificate of the directory server, e.g. of a local Pebble.
+    pub ca_pem_path: Option<String>,
+}
+
+/// Peers whose `X-Forwarded-*` headers are trusted, i.e. the reverse proxies.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    /// Parses comma separated IPs and CIDRs, e.g. `127.0.0.1,10.0.0.0/8`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let nets = value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<IpNet>()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|err| format!("unable to parse trusted proxy '{value}': {err}")))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self(Arc::new(nets)))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Each proxy appends the address it got the request from to `X-Forwarded-For`,
+    /// so the client is the last address which isn't a trusted proxy.
+    pub fn forwarded_client(&self, forwarded_for: &str) -> Option<IpAddr> {
+        let mut client = None;
+        for addr in forwarded_for.rsplit(',') {
+            let addr = addr.trim().parse::<IpAddr>().ok()?;
+            client = Some(addr);
+            if !self.contains(addr) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// Serves `app` with the peer address available as `ConnectInfo<SocketAddr>`.
+/// Once `shutdown` is cancelled no connections are accepted and in-flight requests
+/// get `SHUTDOWN_TIMEOUT` to finish before this returns.
+pub async fn serve(
+    address: SocketAddr,
+    listener: Listener,
+    app: Router,
+    shutdown: CancellationToken,
+) -> Result<(), String> {
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.cancelled().await;
+            info!("stopping web server, waiting for in-flight requests...");
+            handle.graceful_shutdown(Some(SHUTDOWN_TIMEOUT));
+        }
+    });
+
+    let res = match listener {
+        Listener::Tls { cert_pem_path, key_pem_path } => {
+            let tls_cfg = RustlsConfig::from_pem_file(
+                Path::new(&cert_pem_path),
+                Path::new(&key_pem_path),
+            ).await.map_err(|err| format!("unable to create tls config: {err}"))?;
+            tokio::spawn(reload_tls_on_change(tls_cfg.clone(), cert_pem_path, key_pem_path));
+
+            axum_server::bind_rustls(address, tls_cfg).handle(handle).serve(app).await
+        }
+        Listener::Acme(acme_cfg) => {
+            let acceptor = acme_acceptor(acme_cfg)?;
+
+            axum_server::bind(address).acceptor(acceptor).handle(handle).serve(app).await
+        }
+        Listener::Http(_) => axum_server::bind(address).handle(handle).serve(app).await,
+    };
+
+    res.map_err(|err| format!("unable to serve requests: {err}"))
+}
+
+/// Cancels the token on SIGTERM, sent by systemd and docker on stop, or on ctrl-c.
+pub async fn cancel_on_signal(shutdown: CancellationToken) {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("unable to listen to SIGTERM");
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("got SIGTERM, shutting down..."),
+        _ = tokio::signal::ctrl_c() => info!("got SIGINT, shutting down..."),
+    }
+
+    shutdown.cancel();
+}
+
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Result of each readiness check, `ok` or `failing`, details are only logged.
+/// Served with 503 unless every check passes.
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, &'static str>,
+}
+
+impl Readiness {
+    pub fn new(checks: impl IntoIterator<Item = (&'static str, Result<(), String>)>) -> Self {
+        let mut readiness = Self { ready: true, checks: BTreeMap::new() };
+        for (name, res) in checks {
+            let status = match res {
+                Ok(()) => "ok",
+                Err(err) => {
+                    warn!("readiness check name='{name}' is failing: {err}");
+                    readiness.ready = false;
+                    "failing"
+                }
+            };
+            readiness.checks.insert(name, status);
+        }
+        readiness
+    }
+}
+
+impl IntoResponse for Readiness {
+    fn into_response(self) -> Response {
+        let status = if self.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Fails once shutdown has started, so the proxy stops sending new requests.
+pub fn check_shutdown(shutdown: &CancellationToken) -> Result<(), String> {
+    match shutdown.is_cancelled() {
+        false => Ok(()),
+        true => Err("server is shutting down".to_string()),
+    }
+}
+
+/// The certificate is ordered in the background, a cached one is deployed right away.
+/// Renewal is checked by the same task as long as the server runs.
+fn acme_acceptor(cfg: AcmeConfig) -> Result<AxumAcceptor, String> {
+    let mut acme = rustls_acme::AcmeConfig::new(cfg.domains)
+        .contact(cfg.contact_email.iter().map(|email| format!("mailto:{email}")))
+        .cache(DirCache::new(cfg.cache_dir));
+
+    acme = match cfg.directory_url {
+        Some(url) => acme.directory(url),
+        None => acme.directory_lets_encrypt(true),
+    };
+
+    if let Some(path) = cfg.ca_pem_path {
+        acme = acme.client_tls_config(acme_client_tls_config(&path)?);
+    }
+
+    let mut state = acme.state();
+    let acceptor = state.axum_acceptor(state.default_rustls_config());
+
+    tokio::spawn(async move {
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => info!("got acme event: {event:?}"),
+                Err(err) => error!("got acme error: {err:?}"),
+            }
+        }
+    });
+
+    Ok(acceptor)
+}
+
+fn acme_client_tls_config(ca_pem_path: &str) -> Result<Arc<ClientConfig>, String> {
+    let pem = std::fs::read(ca_pem_path)
+        .map_err(|err| format!("unable to read ca pem with path='{ca_pem_path}': {err}"))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let cert = cert.map_err(|err| format!("unable to parse ca pem: {err}"))?;
+        roots.add(cert).map_err(|err| format!("unable to add ca certificate: {err}"))?;
+    }
+
+    Ok(Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()))
+}
+
+/// Polls modification time of the pem files, certbot replaces them on renewal.
+async fn reload_tls_on_change(tls_cfg: RustlsConfig, cert_pem_path: String, key_pem_path: String) {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last = (modified(&cert_pem_path), modified(&key_pem_path));
+
+    let mut interval = tokio::time::interval(TLS_RELOAD_PERIOD);
+    loop {
+        interval.tick().await;
+
+        let current = (modified(&cert_pem_path), modified(&key_pem_path));
+        if current == last {
+            continue;
+        }
+
+        match tls_cfg.reload_from_pem_file(&cert_pem_path, &key_pem_path).await {
+            Ok(()) => {
+                info!("tls certificate has been reloaded from path={cert_pem_path}");
+                last = current;
+            }
+            // the files may be caught half-written, they are read again on the next tick
+            Err(err) => error!("unable to reload tls certificate: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use crate::{AcmeConfig, Readiness, TrustedProxies, acme_acceptor};
+
+    #[test]
+    fn trust_forwarded_for_only_from_proxies() {
+        let proxies = TrustedProxies::parse("127.0.0.1, 10.0.0.0/8").unwrap();
+
+        assert!(proxies.contains("127.0.0.1".parse().unwrap()));
+        assert!(proxies.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!proxies.contains("203.0.113.7".parse().unwrap()));
+
+        let client = proxies.forwarded_client("198.51.100.1, 203.0.113.7, 10.0.0.1");
+        assert_eq!(client, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(proxies.forwarded_client("10.0.0.2, 10.0.0.1"), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(proxies.forwarded_client("unknown"), None);
+
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn readiness_fails_with_any_check() {
+        let readiness = Readiness::new([("db", Ok(())), ("telegram", Ok(()))]);
+        assert!(readiness.ready);
+        assert_eq!(readiness.into_response().status(), StatusCode::OK);
+
+        let readiness = Readiness::new([("db", Ok(())), ("telegram", Err("rejected".to_string()))]);
+        assert!(!readiness.ready);
+        assert_eq!(readiness.checks["db"], "ok");
+        assert_eq!(readiness.checks["telegram"], "failing");
+        assert_eq!(readiness.into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// Runs against a local Pebble which skips challenge validation:
+    /// `PEBBLE_VA_ALWAYS_VALID=1 pebble -config test/config/pebble-config.json`, then
+    /// `PEBBLE_DIRECTORY_URL=https://localhost:14000/dir PEBBLE_CA_PEM_PATH=test/certs/pebble.minica.pem
+    /// cargo test acme_with_pebble -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn acme_with_pebble() {
+        let dir = std::env::temp_dir().join(format!("servekit_acme_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let _acceptor = acme_acceptor(AcmeConfig {
+            domains: vec!["servekit.test".into()],
+            contact_email: Some("admin@servekit.test".into()),
+            cache_dir: dir.to_str().unwrap().into(),
+            directory_url: Some(std::env::var("PEBBLE_DIRECTORY_URL").unwrap()),
+            ca_pem_path: Some(std::env::var("PEBBLE_CA_PEM_PATH").unwrap()),
+        }).unwrap();
+
+        let is_cert_cached = || std::fs::read_dir(&dir)
+            .map(|entries| entries
+                .filter_map(Result::ok)
+                .any(|e| e.file_name().to_string_lossy().starts_with("cached_cert_"))
+            )
+            .unwrap_or(false);
+
+        for _ in 0..60 {
+            if is_cert_cached() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        panic!("certificate hasn't been issued");
+    }
+}
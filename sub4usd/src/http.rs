@@ -0,0 +1,117 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tracing::error;
+
+/// Connections are handled one by one, so a stalled client holds the others only this long.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub tg_updates: IntCounter,
+    /// Telegram messages by `status`: sent, failed.
+    pub tg_messages: IntCounterVec,
+    /// Set to 0 when telegram rejects the token.
+    pub tg_token_valid: IntGauge,
+    pub provider_fetch_seconds: Histogram,
+    pub provider_errors: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("sub4usd".into()), None)
+            .expect("unable to create metrics registry");
+
+        let tg_updates = IntCounter::new("tg_updates_total", "Telegram updates handled").unwrap();
+        let tg_messages = IntCounterVec::new(
+            Opts::new("tg_messages_total", "Telegram messages sent or failed to be sent"),
+            &["status"],
+        ).unwrap();
+        let tg_token_valid = IntGauge::new("tg_token_valid", "Whether telegram accepts the bot token").unwrap();
+        let provider_fetch_seconds = Histogram::with_opts(
+            HistogramOpts::new("provider_fetch_seconds", "Time of usd rate requests")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+        ).unwrap();
+        let provider_errors = IntCounter::new("provider_errors_total", "Failed usd rate requests").unwrap();
+
+        registry.register(Box::new(tg_updates.clone())).unwrap();
+        registry.register(Box::new(tg_messages.clone())).unwrap();
+        registry.register(Box::new(tg_token_valid.clone())).unwrap();
+        registry.register(Box::new(provider_fetch_seconds.clone())).unwrap();
+        registry.register(Box::new(provider_errors.clone())).unwrap();
+
+        Self { registry, tg_updates, tg_messages, tg_token_valid, provider_fetch_seconds, provider_errors }
+    }
+
+    pub fn message_sent(&self, ok: bool) {
+        self.tg_messages
+            .with_label_values(&[if ok { "sent" } else { "failed" }])
+            .inc();
+    }
+
+    fn render(&self) -> Result<String, String> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .map_err(|err| format!("unable to encode metrics: {err}"))?;
+
+        String::from_utf8(buf).map_err(|err| format!("unable to encode metrics: {err}"))
+    }
+}
+
+/// Serves `/healthz`, `/readyz` and `/metrics` until the process exits.
+/// Ready while telegram accepts the token and no shutdown is requested.
+pub fn serve(listener: TcpListener, metrics: Metrics, shutdown: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = handle(stream, &metrics, &shutdown) {
+                    error!("unable to handle http request: {err}");
+                }
+            }
+            Err(err) => error!("unable to accept http connection: {err}"),
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, metrics: &Metrics, shutdown: &AtomicBool) -> Result<(), String> {
+    stream.set_read_timeout(Some(STREAM_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(STREAM_TIMEOUT)))
+        .map_err(|err| format!("unable to set stream timeouts: {err}"))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut request_line)
+        .map_err(|err| format!("unable to read request: {err}"))?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, content_type, body) = match path {
+        "/healthz" => ("200 OK", "text/plain", "ok".to_string()),
+        "/readyz" => {
+            let ready = metrics.tg_token_valid.get() == 1 && !shutdown.load(Ordering::Relaxed);
+            match ready {
+                true => ("200 OK", "text/plain", "ok".to_string()),
+                false => ("503 Service Unavailable", "text/plain", "not ready".to_string()),
+            }
+        }
+        "/metrics" => match metrics.render() {
+            Ok(body) => ("200 OK", prometheus::TEXT_FORMAT, body),
+            Err(err) => {
+                error!("{err}");
+                ("500 Internal Server Error", "text/plain", String::new())
+            }
+        },
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    ).map_err(|err| format!("unable to write response: {err}"))
+}
@@ -1,17 +1,21 @@
 use std::{env, thread};
 use std::cmp::max;
+use std::net::TcpListener;
 use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::Timelike;
 use frankenstein::{BotCommand, ChatId, Error, GetUpdatesParams, SendMessageParams, SetMyCommandsParams, TelegramApi, UpdateContent};
 use regex::Regex;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use tracing::{error, info};
 
 use crate::exchange::RateData;
 
 mod exchange;
+mod http;
 
 enum ChanEvent {
     Price(RateData),
@@ -24,6 +28,9 @@ struct State {
     price_update_interval: Duration,
 }
 
+/// Granularity of sleeps so the loops notice a shutdown request in time.
+const SHUTDOWN_POLL: Duration = Duration::from_secs(1);
+
 fn main() {
     tracing_subscriber::fmt::init();
 
@@ -39,6 +46,23 @@ fn main() {
         .map(|s| s.parse::<i64>().unwrap())
         .collect();
 
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for sig in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(sig, shutdown.clone())
+            .expect("unable to register signal handler");
+    }
+
+    let metrics = http::Metrics::new();
+    if let Ok(address) = env::var("HTTP_ADDRESS") {
+        let listener = TcpListener::bind(&address)
+            .expect("unable to bind http listener");
+        info!("serving health and metrics on address={address}");
+
+        let metrics = metrics.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || http::serve(listener, metrics, shutdown));
+    }
+
     let state = Arc::new(Mutex::new(State {
         price_update_interval: Duration::from_secs(3 * 60 * 60),
     }));
@@ -49,17 +73,47 @@ fn main() {
     let tg_api_clone = tg_api.clone();
     let tx_clone = tx.clone();
     let state_clone = state.clone();
+    let shutdown_clone = shutdown.clone();
+    let updater_metrics = metrics.clone();
+    let notifier_metrics = metrics.clone();
+
+    let updater = thread::spawn(move || run_usd_price_updater(tx, provider, state, &shutdown_clone, &updater_metrics));
+    let notifier = thread::spawn(move || run_tg_notifier(rx, tg_api, tg_chats, &notifier_metrics));
+
+    run_tg_loop(tx_clone, tg_api_clone, state_clone, &shutdown, &metrics);
+
+    info!("waiting for the price updater...");
+    updater.join().expect("unable to join price updater");
 
-    thread::spawn(move || run_usd_price_updater(tx, provider, state));
-    thread::spawn(move || run_tg_notifier(rx, tg_api, tg_chats));
+    // the notifier drains queued events and stops once every sender is dropped
+    info!("waiting for the notifier to send queued messages...");
+    notifier.join().expect("unable to join notifier");
 
-    run_tg_loop(tx_clone, tg_api_clone, state_clone);
+    info!("stopped");
+}
+
+/// Sleeps for `dur` or until shutdown is requested, returns false in the latter case.
+fn sleep_unless_shutdown(shutdown: &AtomicBool, dur: Duration) -> bool {
+    let deadline = Instant::now() + dur;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+
+        thread::sleep(SHUTDOWN_POLL.min(deadline - now));
+    }
+
+    false
 }
 
 fn run_tg_loop(
     tx: Sender<ChanEvent>,
     tg_api: Arc<frankenstein::Api>,
     state: Arc<Mutex<State>>,
+    shutdown: &AtomicBool,
+    metrics: &http::Metrics,
 ) {
     const SUBSCRIBE: &str = "subscribe";
     const UNSUBSCRIBE: &str = "unsubscribe";
@@ -73,6 +127,7 @@ fn run_tg_loop(
         ])
         .build(),
     ).expect("unable to set commands");
+    metrics.tg_token_valid.set(1);
 
     let handle_command = |chat_id: i64, text: String| {
         if text == format!("/{SUBSCRIBE}") {
@@ -112,13 +167,16 @@ fn run_tg_loop(
 
     let mut update_params = GetUpdatesParams::builder().build();
 
-    loop {
+    while !shutdown.load(Ordering::Relaxed) {
         let res = tg_api
             .get_updates(&update_params);
 
         match res {
             Ok(res) => {
+                metrics.tg_token_valid.set(1);
+
                 for update in res.result {
+                    metrics.tg_updates.inc();
                     update_params = GetUpdatesParams::builder()
                         .offset(update.update_id + 1)
                         .build();
@@ -131,22 +189,40 @@ fn run_tg_loop(
                 }
             }
             Err(err) => {
+                if let Error::Api(resp) = &err {
+                    if resp.error_code == 401 || resp.error_code == 404 {
+                        metrics.tg_token_valid.set(0);
+                    }
+                }
                 error!("error while getting updates from tg: {:?}", err);
-                thread::sleep(Duration::from_secs(5 * 60))
+                if !sleep_unless_shutdown(shutdown, Duration::from_secs(5 * 60)) {
+                    break;
+                }
             }
         }
 
         let hour = chrono::Local::now().hour();
         let sleep_dur_sec = if let 0..=6 = hour { max(1, 6 - hour) * 60 * 60 } else { 3 };
 
-        thread::sleep(Duration::from_secs(sleep_dur_sec as u64));
+        sleep_unless_shutdown(shutdown, Duration::from_secs(sleep_dur_sec as u64));
+    }
+
+    // confirm handled updates so they aren't redelivered after restart
+    if let Some(offset) = update_params.offset {
+        let params = GetUpdatesParams::builder().offset(offset).limit(1u32).timeout(0u32).build();
+        if let Err(err) = tg_api.get_updates(&params) {
+            error!("unable to confirm handled updates: {:?}", err);
+        }
     }
+
+    info!("tg loop has been stopped");
 }
 
 fn run_tg_notifier(
     rx: Receiver<ChanEvent>,
     tg_api: Arc<frankenstein::Api>,
     default_chats: Vec<i64>,
+    metrics: &http::Metrics,
 ) {
     let mut chats: Vec<i64> = default_chats;
     let mut last_price = 0.0;
@@ -158,6 +234,7 @@ fn run_tg_notifier(
             .text(text)
             .build(),
         );
+        metrics.message_sent(res.is_ok());
 
         if let Err(Error::Api(err)) = res {
             error!("sending event to tg: {:?}", err);
@@ -174,9 +251,7 @@ fn run_tg_notifier(
         format!("{price} :: {info}")
     };
 
-    loop {
-        let event = rx.recv().expect("unable to receive event");
-
+    while let Ok(event) = rx.recv() {
         match event {
             ChanEvent::Price(rate) => {
                 last_price = rate.0;
@@ -204,23 +279,37 @@ fn run_tg_notifier(
             }
         }
     }
+
+    info!("notifier has been stopped");
 }
 
 fn run_usd_price_updater(
     tx: Sender<ChanEvent>,
     provider: Box<dyn exchange::RateProvider>,
     state: Arc<Mutex<State>>,
+    shutdown: &AtomicBool,
+    metrics: &http::Metrics,
 ) {
     let mut prev_price: f64 = 0.0;
 
-    loop {
-        let rate = provider.get_usd_rate()
-            .expect("unable to get rate");
-        let price = rate.0;
+    while !shutdown.load(Ordering::Relaxed) {
+        let timer = metrics.provider_fetch_seconds.start_timer();
+        let res = provider.get_usd_rate();
+        timer.observe_duration();
+
+        match res {
+            Ok(rate) => {
+                let price = rate.0;
 
-        if price.ne(&prev_price) {
-            tx.send(ChanEvent::Price(rate)).expect("unable to send price to channel");
-            prev_price = price;
+                if price.ne(&prev_price) {
+                    tx.send(ChanEvent::Price(rate)).expect("unable to send price to channel");
+                    prev_price = price;
+                }
+            }
+            Err(err) => {
+                metrics.provider_errors.inc();
+                error!("unable to get usd rate: {err}");
+            }
         }
 
         let dur = {
@@ -228,8 +317,10 @@ fn run_usd_price_updater(
             state.price_update_interval
         };
 
-        thread::sleep(dur);
+        sleep_unless_shutdown(shutdown, dur);
     }
+
+    info!("price updater has been stopped");
 }
 
 #[cfg(test)]